@@ -0,0 +1,114 @@
+//! Dispatch of changed keys to pattern subscriptions.
+//! `regex_vec` is the previous approach: every pattern of a subscription is precompiled and tried in turn,
+//! `regex_set` is the current one: patterns are compiled once into a `RegexSet` per subscription.
+//! `watcher_poll` runs the watcher itself: one `poll_now` with the changed keys dispatched to pattern subscriptions.
+use std::collections::HashMap;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use env_watcher::{EnvSource, EnvironmentWatcher, Result, Subscribe};
+use regex::{Regex, RegexSet};
+
+const SUBSCRIPTIONS: usize = 50;
+const CHANGES: usize = 20;
+
+fn patterns() -> Vec<Vec<String>> {
+    (0..SUBSCRIPTIONS)
+        .map(|i| {
+            vec![
+                format!("^service{}\\.server\\..*", i),
+                format!("^service{}\\.db\\..*", i),
+                format!("^SERVICE{}_.*", i),
+            ]
+        })
+        .collect()
+}
+
+fn changed_keys() -> Vec<String> {
+    (0..CHANGES)
+        .map(|i| format!("service{}.server.port", i * 3))
+        .collect()
+}
+
+fn regex_vec(c: &mut Criterion) {
+    let subs = patterns()
+        .iter()
+        .map(|sub| sub.iter().map(|pattern| Regex::new(pattern).unwrap()).collect())
+        .collect::<Vec<Vec<Regex>>>();
+    let keys = changed_keys();
+
+    c.bench_function("regex_vec", |b| {
+        b.iter(|| {
+            let mut sent = 0;
+            for regexes in subs.iter() {
+                for key in keys.iter() {
+                    if regexes.iter().any(|reg| reg.is_match(key)) {
+                        sent += 1;
+                    }
+                }
+            }
+            black_box(sent)
+        })
+    });
+}
+
+fn regex_set(c: &mut Criterion) {
+    let sets = patterns()
+        .iter()
+        .map(|sub| RegexSet::new(sub).unwrap())
+        .collect::<Vec<RegexSet>>();
+    let keys = changed_keys();
+
+    c.bench_function("regex_set", |b| {
+        b.iter(|| {
+            let mut sent = 0;
+            for set in sets.iter() {
+                for key in keys.iter() {
+                    if set.is_match(key) {
+                        sent += 1;
+                    }
+                }
+            }
+            black_box(sent)
+        })
+    });
+}
+
+/// Source changing the value of every key on each snapshot
+struct Ticking {
+    keys: Vec<String>,
+    tick: u64,
+}
+
+impl EnvSource for Ticking {
+    fn name(&self) -> &str {
+        "ticking"
+    }
+
+    fn snapshot(&mut self) -> Result<HashMap<String, String>> {
+        self.tick += 1;
+        Ok(self.keys.iter().map(|key| (key.clone(), self.tick.to_string())).collect())
+    }
+}
+
+fn watcher_poll(c: &mut Criterion) {
+    let source = Ticking {
+        keys: changed_keys(),
+        tick: 0,
+    };
+    let watcher = EnvironmentWatcher::builder().manual(true).source(source).build().unwrap();
+    let subscriptions = patterns()
+        .into_iter()
+        .map(|sub| watcher.subscribe_batch(Subscribe::PatternEnvs(sub)).unwrap())
+        .collect::<Vec<_>>();
+
+    c.bench_function("watcher_poll", |b| {
+        b.iter(|| {
+            black_box(watcher.poll_now());
+            for subscription in subscriptions.iter() {
+                subscription.receiver().try_iter().for_each(drop);
+            }
+        })
+    });
+}
+
+criterion_group!(benches, regex_vec, regex_set, watcher_poll);
+criterion_main!(benches);
@@ -4,11 +4,11 @@ use std::env::{remove_var, set_var};
 use std::thread::sleep;
 use std::time::Duration;
 
-static TEST_VALUE: &'static str = "ONLY_TEST";
+static TEST_VALUE: &str = "ONLY_TEST";
 
 fn fill_envs(envs: Vec<String>, val: &'static str) {
     envs.iter().for_each(|k| {
-        std::env::set_var(k, &val);
+        std::env::set_var(k, val);
     });
 }
 
@@ -44,11 +44,11 @@ pub fn main() -> Result<(), Error> {
 
     print_after();
 
-    let mut data = only_envs.0;
+    let mut data = only_envs.data().clone();
 
     println!("{:?}", &data);
 
-    let rec = only_envs.1;
+    let rec = only_envs.receiver();
 
     let mut event_count = 0;
     loop {
@@ -64,7 +64,7 @@ pub fn main() -> Result<(), Error> {
         let event = rec.recv().unwrap();
 
         match event {
-            ChangeState::Edit(k, v) => {
+            ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
                 match &*k {
                     "server.port" => {
                         assert_eq!("2013", &*v);
@@ -81,14 +81,14 @@ pub fn main() -> Result<(), Error> {
                     }
                 }
             }
-            ChangeState::Delete(k) => {
+            ChangeState::Removed { key: k, .. } => {
                 assert_eq!("server.tls", &*k);
                 data.remove(&k);
                 restart_server(&data);
             }
         }
 
-        event_count = event_count + 1;
+        event_count += 1;
 
         if event_count >= 3 {
             break;
@@ -11,23 +11,23 @@ fn main() -> Result<(), Error> {
 
     let sub = Subscribe::Envs(vec!["key.key".to_string()]);
 
-    let (env, rx) = sub_env!(sub)?;
+    let subscription = sub_env!(sub)?;
 
-    assert_eq!(Some(&String::from("vvv")), env.get("key.key"));
+    assert_eq!(Some(&String::from("vvv")), subscription.data().get("key.key"));
 
     set_var("key.key", "hello");
 
-    let event = rx.recv().unwrap();
+    let event = subscription.receiver().recv().unwrap();
 
     match event {
-        ChangeState::Edit(k, v) => {
+        ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
             let k = &*k;
 
             if k.eq("key.key") {
                 assert_eq!("hello", &*v)
             }
         }
-        ChangeState::Delete(_) => {}
+        ChangeState::Removed { .. } => {}
     }
 
     Ok(())
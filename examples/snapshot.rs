@@ -2,12 +2,12 @@ use env_watcher::{EnvironmentWatcher, Error, Subscribe};
 use std::thread::sleep;
 use std::time::Duration;
 
-static TEST_VALUE: &'static str = "ONLY_TEST";
-static TEST_BY_CHANGE_VALUE: &'static str = "ONLY_TEST_CHANGE";
+static TEST_VALUE: &str = "ONLY_TEST";
+static TEST_BY_CHANGE_VALUE: &str = "ONLY_TEST_CHANGE";
 
 fn fill_envs(envs: Vec<String>, val: &'static str) {
     envs.iter().for_each(|k| {
-        std::env::set_var(k, &val);
+        std::env::set_var(k, val);
     });
 }
 
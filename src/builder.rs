@@ -0,0 +1,213 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::info;
+use std::fmt;
+use crate::schedule::Schedule;
+use crate::source::{EnvSource, Layer, ProcessEnv};
+use crate::{EnvironmentWatcher, Matcher, Result, Shared, Subscribe, DEFAULT_THREAD_NAME};
+
+/// Channels created for subscribers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelPolicy {
+    /// Unbounded channels, a slow subscriber never loses changes
+    Unbounded,
+
+    /// Channels with the given capacity.
+    /// The watcher never blocks on a slow subscriber: if its channel is full, new changes for it are dropped with a warning.
+    /// Snapshots of `subscribe_snapshot` are always unbounded, they must not miss a change.
+    Bounded(usize),
+}
+
+/// Builder for `EnvironmentWatcher`
+/// let watcher = EnvironmentWatcher::builder().interval(Duration::from_secs(5)).thread_name("config-watcher").build()?;
+pub struct EnvironmentWatcherBuilder {
+    /// How often we request data and update the state
+    interval: Duration,
+
+    /// Adaptive mode: ceiling of the interval while the environment is quiet
+    adaptive: Option<Duration>,
+
+    /// Random deviation of every poll delay, a fraction of it
+    jitter: f64,
+
+    /// Read the environment before the first tick
+    preload: bool,
+
+    /// Start the polling thread on build
+    autostart: bool,
+
+    /// Never spawn the polling thread
+    manual: bool,
+
+    /// Name of the polling thread
+    thread_name: String,
+
+    /// Channels created for subscribers
+    channel: ChannelPolicy,
+
+    /// Only variables matching the filter are tracked
+    filter: Option<Subscribe>,
+
+    /// Layers of variables in precedence order, `ProcessEnv` if empty
+    layers: Vec<Layer>,
+
+    /// Resolve `X_FILE` variables into `X`
+    resolve_files: bool,
+}
+
+impl fmt::Debug for EnvironmentWatcherBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let layers = self.layers.iter().map(|layer| &**layer.name()).collect::<Vec<&str>>();
+        f.debug_struct("EnvironmentWatcherBuilder")
+            .field("interval", &self.interval)
+            .field("adaptive", &self.adaptive)
+            .field("jitter", &self.jitter)
+            .field("preload", &self.preload)
+            .field("autostart", &self.autostart)
+            .field("manual", &self.manual)
+            .field("thread_name", &self.thread_name)
+            .field("channel", &self.channel)
+            .field("filter", &self.filter)
+            .field("layers", &layers)
+            .field("resolve_files", &self.resolve_files)
+            .finish()
+    }
+}
+
+impl Default for EnvironmentWatcherBuilder {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(5 * 100),
+            adaptive: None,
+            jitter: 0.0,
+            preload: true,
+            autostart: true,
+            manual: false,
+            thread_name: DEFAULT_THREAD_NAME.to_string(),
+            channel: ChannelPolicy::Unbounded,
+            filter: None,
+            layers: Vec::new(),
+            resolve_files: false,
+        }
+    }
+}
+
+impl EnvironmentWatcherBuilder {
+    /// How often we request data and update the state. Default - 500 milliseconds
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Adaptive polling: right after a change the environment is polled every `interval`,
+    /// while it is quiet the interval doubles on every poll up to `ceiling`. Default - fixed interval.
+    pub fn adaptive(mut self, ceiling: Duration) -> Self {
+        self.adaptive = Some(ceiling);
+        self
+    }
+
+    /// Random deviation of every poll delay, as a fraction of it in `0.0..=1.0`,
+    /// so many processes started together do not poll in lockstep. Non-finite values disable jitter. Default - `0.0`.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Read the environment on build. Default - `true`.
+    /// Without preload the first tick reports every variable as added.
+    pub fn preload(mut self, preload: bool) -> Self {
+        self.preload = preload;
+        self
+    }
+
+    /// Start the polling thread on build. Default - `true`.
+    /// Otherwise the thread is started by `EnvironmentWatcher::run`.
+    pub fn autostart(mut self, autostart: bool) -> Self {
+        self.autostart = autostart;
+        self
+    }
+
+    /// Manual mode: the watcher never spawns a thread, changes are found only by `EnvironmentWatcher::poll_now`.
+    /// Useful for event loops with their own timer and for deterministic tests. Default - `false`.
+    pub fn manual(mut self, manual: bool) -> Self {
+        self.manual = manual;
+        self
+    }
+
+    /// Name of the polling thread. Default - `env-watcher`
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Channels created for subscribers. Default - `ChannelPolicy::Unbounded`
+    pub fn channel(mut self, channel: ChannelPolicy) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Track only variables matching the filter, other variables are invisible to every subscriber.
+    /// Default - all variables.
+    pub fn filter(mut self, filter: Subscribe) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Add a source of variables, sources added later override the variables of earlier ones.
+    /// Default - only the process environment, `ProcessEnv`. Once a source is added, `ProcessEnv` is read only if added too.
+    /// Write-through `set`, `remove` and `apply` always write the process environment,
+    /// without a `ProcessEnv` source the written variables are invisible to the watcher and no events are sent.
+    /// The layer is named by `EnvSource::name`.
+    pub fn source<S: EnvSource + 'static>(self, source: S) -> Self {
+        let name = source.name().to_string();
+        self.layer(name, source)
+    }
+
+    /// Add a named layer of variables, layers added later override the variables of earlier ones.
+    /// The effective value of a key comes from the last layer defining it, `EnvironmentWatcher::origin` reports the layer name.
+    /// let watcher = EnvironmentWatcher::builder().layer("defaults", defaults).layer("env", ProcessEnv).layer("secrets", secrets).build()?;
+    pub fn layer<S: EnvSource + 'static>(mut self, name: impl Into<String>, source: S) -> Self {
+        self.layers.push(Layer::new(name.into(), source));
+        self
+    }
+
+    /// Resolve the `*_FILE` convention for secrets: `X_FILE=/run/secrets/x` adds a variable `X` with the content of the file,
+    /// without trailing line breaks. The file is read on every poll, so `X` changes when either the pointer or the file changes.
+    /// A later layer wins, whether it defines `X` or `X_FILE`. If one layer defines both, the explicit `X` wins and a warning is logged.
+    /// Default - `false`.
+    pub fn resolve_files(mut self, resolve: bool) -> Self {
+        self.resolve_files = resolve;
+        self
+    }
+
+    /// Create the watcher. Fails if the filter is invalid.
+    pub fn build(self) -> Result<EnvironmentWatcher> {
+        let filter = self.filter.as_ref().map(Matcher::compile).transpose()?;
+        Ok(self.start(filter))
+    }
+
+    /// Create the watcher with the compiled filter
+    pub(crate) fn start(mut self, filter: Option<Matcher>) -> EnvironmentWatcher {
+        info!("Starting env watcher with interval {:?}", &self.interval);
+        if self.layers.is_empty() {
+            self.layers.push(Layer::new(ProcessEnv.name(), ProcessEnv));
+        }
+        let schedule = Schedule::new(self.interval, self.adaptive, self.jitter);
+        let watcher = EnvironmentWatcher {
+            shared: Arc::new(Shared::new(filter, schedule, self.layers, self.resolve_files)),
+            next_id: AtomicU64::new(0),
+            thread_name: self.thread_name,
+            channel: self.channel,
+            manual: self.manual,
+            worker: Mutex::new(None),
+        };
+        if self.preload {
+            watcher.preload();
+        }
+        if self.autostart {
+            watcher.run();
+        }
+        watcher
+    }
+}
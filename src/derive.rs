@@ -1,9 +1,7 @@
-use std::collections::HashMap;
 use std::sync::Mutex;
 use std::time::Duration;
-use crossbeam_channel::Receiver;
 use state::Storage;
-use crate::{ChangeState, EnvironmentData, EnvironmentWatcher, Subscribe, Result, Error};
+use crate::{EnvironmentData, EnvironmentWatcher, Subscribe, Subscription, Result, Error};
 
 static ENV_WATCHER: Storage<Mutex<EnvironmentWatcher>> = Storage::new();
 static INIT: Storage<Mutex<i8>> = Storage::new();
@@ -18,7 +16,7 @@ macro_rules! init_env_watch {
     }
 }
 
-/// Subscribe with Receiver and Data
+/// Subscribe with Receiver and Data, returns `Subscription`
 #[macro_export]
 macro_rules! sub_env {
     ($sub:expr) => {
@@ -58,7 +56,7 @@ pub fn init_env_watcher(interval: Duration) -> Result<()> {
 }
 
 #[doc(hidden)]
-pub fn subscribe(sub: Subscribe) -> Result<(HashMap<String, String>, Receiver<ChangeState>)> {
+pub fn subscribe(sub: Subscribe) -> Result<Subscription> {
     let watcher = ENV_WATCHER.get().lock().unwrap();
     watcher.subscribe(sub)
 }
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use log::warn;
+use crate::{EnvSource, Error, Result};
+
+/// Link to the current data in a Kubernetes volume of a ConfigMap or Secret
+const DATA_LINK: &str = "..data";
+
+/// How many times the directory is read again if the data is swapped while reading
+const SWAP_RETRIES: usize = 5;
+
+/// Variables of a directory with one file per variable: the file name is the key, its content the value.
+///
+/// Understands mounted Kubernetes ConfigMaps and Secrets: files are read through the `..data` link,
+/// which is resolved once per snapshot. If the link is swapped to new data while reading, the directory is read again,
+/// so a snapshot never mixes old and new files. Entries starting with `..`, subdirectories and files
+/// which are not valid UTF-8 are skipped.
+#[derive(Debug, Clone)]
+pub struct DirectorySource {
+    /// Watched directory
+    path: PathBuf,
+
+    /// Name of the source, the path
+    name: String,
+
+    /// Remove trailing line breaks from values
+    trim_newlines: bool,
+
+    /// Target of the `..data` link read last
+    data: Option<PathBuf>,
+}
+
+impl DirectorySource {
+    /// Watch the directory at the path
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Self {
+            name: path.display().to_string(),
+            path,
+            trim_newlines: false,
+            data: None,
+        }
+    }
+
+    /// Remove trailing line breaks from values, `echo` and editors usually add one. Default - `false`.
+    pub fn trim_newlines(mut self, trim: bool) -> Self {
+        self.trim_newlines = trim;
+        self
+    }
+
+    /// Path to the directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Target of the `..data` link, `None` for a plain directory
+    fn data_target(&self) -> Option<PathBuf> {
+        std::fs::read_link(self.path.join(DATA_LINK)).ok()
+    }
+
+    /// Read every file of the directory
+    fn read_dir(&self, dir: &Path) -> Result<HashMap<String, String>> {
+        let mut vars = HashMap::new();
+        for entry in std::fs::read_dir(dir).map_err(|e| self.error(e))? {
+            let entry = entry.map_err(|e| self.error(e))?;
+            let Ok(key) = entry.file_name().into_string() else {
+                warn!("Skipping file with invalid name {:?} in {:?}", entry.file_name(), self.name);
+                continue;
+            };
+            if key.starts_with("..") {
+                continue;
+            }
+            // Follows links, keys in the volume root are links into `..data`
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            let bytes = std::fs::read(&path).map_err(|e| self.error(e))?;
+            let Ok(mut value) = String::from_utf8(bytes) else {
+                warn!("Skipping file {:?} in {:?}, the content is not valid UTF-8", key, self.name);
+                continue;
+            };
+            if self.trim_newlines {
+                let len = value.trim_end_matches(['\n', '\r']).len();
+                value.truncate(len);
+            }
+            vars.insert(key, value);
+        }
+        Ok(vars)
+    }
+
+    fn error(&self, error: std::io::Error) -> Error {
+        Error::Source {
+            name: self.name.clone(),
+            error: error.to_string(),
+        }
+    }
+}
+
+impl EnvSource for DirectorySource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn snapshot(&mut self) -> Result<HashMap<String, String>> {
+        for _ in 0..SWAP_RETRIES {
+            let Some(target) = self.data_target() else {
+                self.data = None;
+                return self.read_dir(&self.path);
+            };
+            // Read the resolved data directory, the `..data` link may be swapped at any moment
+            let vars = self.read_dir(&self.path.join(&target));
+            if self.data_target().as_ref() == Some(&target) {
+                self.data = Some(target);
+                return vars;
+            }
+        }
+        Err(Error::Source {
+            name: self.name.clone(),
+            error: format!("{} is swapped faster than it can be read", DATA_LINK),
+        })
+    }
+
+    fn changed(&mut self) -> bool {
+        // Kubernetes only swaps the link, a plain directory has to be read every time
+        self.data.is_none() || self.data_target() != self.data
+    }
+}
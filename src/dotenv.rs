@@ -0,0 +1,323 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::time::{Duration, SystemTime};
+use crate::{EnvSource, Error, Result};
+
+/// Variables of a `.env` file, re-read when the file changes.
+///
+/// The file is checked on every poll: a different modification time or size means it has changed.
+/// A file modified shortly before it was read is also compared by content hash,
+/// so edits within the resolution of the file system clock are not missed.
+/// If the file is missing or invalid, the error is logged once and the last variables read are kept.
+#[derive(Debug, Clone)]
+pub struct DotEnvFile {
+    /// Path to the file
+    path: PathBuf,
+
+    /// Name of the source, the path
+    name: String,
+
+    /// State of the file when it was read last
+    stamp: Option<FileStamp>,
+
+    /// Variables read last
+    vars: HashMap<String, String>,
+}
+
+/// State of the file when it was read
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    /// Modification time
+    modified: Option<SystemTime>,
+
+    /// Size in bytes
+    len: u64,
+
+    /// Hash of the content
+    hash: u64,
+
+    /// The file was modified shortly before it was read, a later edit may keep the same time and size
+    racy: bool,
+}
+
+/// Modification times closer than this to the read may hide a following edit
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+impl DotEnvFile {
+    /// Watch the file at the path
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Self {
+            name: path.display().to_string(),
+            path,
+            stamp: None,
+            vars: HashMap::new(),
+        }
+    }
+
+    /// Path to the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the file content
+    fn read(&self) -> Result<String> {
+        std::fs::read_to_string(&self.path).map_err(|e| Error::Source {
+            name: self.name.clone(),
+            error: e.to_string(),
+        })
+    }
+}
+
+impl EnvSource for DotEnvFile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn snapshot(&mut self) -> Result<HashMap<String, String>> {
+        let metadata = std::fs::metadata(&self.path).ok();
+        let content = self.read()?;
+        let hash = hash(&content);
+        let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+        let stamp = FileStamp {
+            modified,
+            len: content.len() as u64,
+            hash,
+            racy: is_racy(modified),
+        };
+        let unchanged = self.stamp.as_ref().is_some_and(|old| old.hash == hash);
+        // The stamp is kept on a parse error too, the invalid file is not re-read until it changes
+        self.stamp = Some(stamp);
+        if !unchanged {
+            self.vars = parse_dotenv(&content)?;
+        }
+        Ok(self.vars.clone())
+    }
+
+    fn changed(&mut self) -> bool {
+        let Some(stamp) = &self.stamp else {
+            return true;
+        };
+        let Ok(metadata) = std::fs::metadata(&self.path) else {
+            return true;
+        };
+        if metadata.modified().ok() != stamp.modified || metadata.len() != stamp.len {
+            return true;
+        }
+        if !stamp.racy {
+            return false;
+        }
+        let hash = match self.read() {
+            Ok(content) => hash(&content),
+            Err(_) => return true,
+        };
+        let Some(stamp) = self.stamp.as_mut() else {
+            return true;
+        };
+        if hash != stamp.hash {
+            return true;
+        }
+        // Once the modification time is out of the window, a later edit changes it
+        stamp.racy = is_racy(stamp.modified);
+        false
+    }
+}
+
+/// Whether the file was modified too recently for the modification time to reveal the next edit
+fn is_racy(modified: Option<SystemTime>) -> bool {
+    modified.is_none_or(|modified| modified + RACY_WINDOW > SystemTime::now())
+}
+
+/// Hash of the file content
+fn hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Parse the content of a `.env` file. If a key is repeated, the last value wins.
+///
+/// Supported syntax:
+/// ```text
+/// # comment
+/// export KEY=value            # comment after whitespace
+/// KEY = 'literal $value, may span lines'
+/// KEY="with escapes \n \t \" \\ and several
+/// lines"
+/// ```
+///
+/// Errors are `Error::Parse` with the line number, counted from 1.
+pub fn parse_dotenv(content: &str) -> Result<HashMap<String, String>> {
+    let mut parser = Parser {
+        chars: content.chars().peekable(),
+        line: 1,
+    };
+    let mut vars = HashMap::new();
+    while let Some((key, value)) = parser.entry()? {
+        vars.insert(key, value);
+    }
+    Ok(vars)
+}
+
+/// Parser of the `.env` format
+struct Parser<'a> {
+    /// Remaining content
+    chars: Peekable<Chars<'a>>,
+
+    /// Current line, from 1
+    line: usize,
+}
+
+impl Parser<'_> {
+    /// Parse the next variable, skipping empty lines and comments
+    fn entry(&mut self) -> Result<Option<(String, String)>> {
+        loop {
+            self.skip_blank();
+            match self.chars.peek() {
+                None => return Ok(None),
+                Some('\n') => {
+                    self.next();
+                }
+                Some('#') => self.skip_line(),
+                Some(_) => break,
+            }
+        }
+
+        let mut key = self.word();
+        if key == "export" && self.chars.peek().is_some_and(|c| *c == ' ' || *c == '\t') {
+            self.skip_blank();
+            key = self.word();
+        }
+        if key.is_empty() {
+            let found = self.chars.peek().copied().unwrap_or('\n');
+            return self.error(format!("expected a key, found {:?}", found));
+        }
+        if key.starts_with(|c: char| c.is_ascii_digit()) {
+            return self.error(format!("invalid key {:?}", key));
+        }
+
+        self.skip_blank();
+        if self.chars.peek() != Some(&'=') {
+            return self.error(format!("expected '=' after {:?}", key));
+        }
+        self.next();
+        self.skip_blank();
+
+        let value = match self.chars.peek() {
+            Some('\'') => self.single_quoted()?,
+            Some('"') => self.double_quoted()?,
+            _ => return Ok(Some((key, self.unquoted()))),
+        };
+        self.skip_blank();
+        match self.chars.peek().copied() {
+            None | Some('\n') => {}
+            Some('#') => self.skip_line(),
+            Some(c) => return self.error(format!("unexpected {:?} after the closing quote", c)),
+        }
+        Ok(Some((key, value)))
+    }
+
+    /// Key characters: letters, digits, `_`, `.` and `-`
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.chars.peek().copied() {
+            if !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-') {
+                break;
+            }
+            word.push(c);
+            self.next();
+        }
+        word
+    }
+
+    /// Value up to the end of the line or a comment after whitespace, trimmed
+    fn unquoted(&mut self) -> String {
+        let mut value = String::new();
+        while let Some(c) = self.chars.peek().copied() {
+            if c == '\n' || (c == '#' && value.ends_with([' ', '\t'])) {
+                break;
+            }
+            value.push(c);
+            self.next();
+        }
+        self.skip_line();
+        value.trim_end().to_string()
+    }
+
+    /// Literal value up to the closing `'`
+    fn single_quoted(&mut self) -> Result<String> {
+        let line = self.line;
+        self.next();
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(value),
+                Some(c) => value.push(c),
+                None => return self.error_at(line, "unterminated single quote".to_string()),
+            }
+        }
+    }
+
+    /// Value with escapes up to the closing `"`
+    fn double_quoted(&mut self) -> Result<String> {
+        let line = self.line;
+        self.next();
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('"' | '\\' | '$' | '\'')) => value.push(c),
+                    Some('\n') => {}
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => return self.error_at(line, "unterminated double quote".to_string()),
+                },
+                Some(c) => value.push(c),
+                None => return self.error_at(line, "unterminated double quote".to_string()),
+            }
+        }
+    }
+
+    /// Next character, counting lines
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    /// Skip spaces and tabs
+    fn skip_blank(&mut self) {
+        while self.chars.peek().is_some_and(|c| *c == ' ' || *c == '\t' || *c == '\r') {
+            self.next();
+        }
+    }
+
+    /// Skip the rest of the line including the line break
+    fn skip_line(&mut self) {
+        while let Some(c) = self.next() {
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+
+    fn error<T>(&self, error: String) -> Result<T> {
+        self.error_at(self.line, error)
+    }
+
+    fn error_at<T>(&self, line: usize, error: String) -> Result<T> {
+        Err(Error::Parse { line, error })
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::{HashMap, HashSet};
+use log::warn;
+
+/// Suffix of variables pointing to a file with the value
+const FILE_SUFFIX: &str = "_FILE";
+
+/// Resolves the `*_FILE` convention: `X_FILE=/path` adds a virtual variable `X` with the content of the file.
+#[derive(Debug, Default)]
+pub(crate) struct FileRefs {
+    /// Last content read per virtual key, with the path it was read from
+    cache: HashMap<String, (String, String)>,
+
+    /// Keys reported as defined both explicitly and by a file in one layer, to warn once
+    conflicts: HashSet<String>,
+
+    /// Virtual keys with the path which cannot be read, to warn once
+    failures: HashSet<(String, String)>,
+}
+
+impl FileRefs {
+    /// Add the virtual variables to the scanned ones.
+    /// The files are read on every scan, so a change of the pointer or of the file content is found by the next poll.
+    /// Trailing line breaks of the content are removed, like `$(< file)` in shell.
+    /// If a file cannot be read, the content read last from the same path is kept.
+    /// Layers keep their precedence: `X` and `X_FILE` from different layers are resolved by the later layer,
+    /// within one layer the explicit `X` wins. A resolved `X` comes from the layer of `X_FILE`.
+    pub(crate) fn resolve(&mut self, vars: &mut HashMap<String, String>, origins: &mut HashMap<String, usize>) {
+        let mut resolved = HashMap::new();
+        for (pointer, path) in vars.iter() {
+            let Some(key) = pointer.strip_suffix(FILE_SUFFIX).filter(|key| !key.is_empty()) else {
+                continue;
+            };
+            let layer = origins[pointer];
+            if let Some(&explicit) = origins.get(key) {
+                if explicit == layer && self.conflicts.insert(key.to_string()) {
+                    warn!("Both {:?} and {:?} are defined, using the explicit {:?}", key, pointer, key);
+                }
+                if explicit >= layer {
+                    continue;
+                }
+            }
+            self.conflicts.remove(key);
+            match std::fs::read_to_string(path) {
+                Ok(mut content) => {
+                    let len = content.trim_end_matches(['\n', '\r']).len();
+                    content.truncate(len);
+                    self.cache.insert(key.to_string(), (path.clone(), content.clone()));
+                    self.failures.remove(&(key.to_string(), path.clone()));
+                    resolved.insert(key.to_string(), (content, layer));
+                }
+                Err(e) => {
+                    let cached = self.cache.get(key).filter(|(cached, _)| cached == path);
+                    if self.failures.insert((key.to_string(), path.clone())) {
+                        match cached {
+                            Some(_) => warn!("Failed to read {:?} for {:?}, using the last content. Error: {}", path, pointer, e),
+                            None => warn!("Failed to read {:?} for {:?}. Error: {}", path, pointer, e),
+                        }
+                    }
+                    if let Some((_, content)) = cached {
+                        resolved.insert(key.to_string(), (content.clone(), layer));
+                    }
+                }
+            }
+        }
+        self.failures.retain(|(key, path)| vars.get(&format!("{}{}", key, FILE_SUFFIX)) == Some(path));
+        self.cache.retain(|key, _| resolved.contains_key(key));
+        self.conflicts.retain(|key| vars.contains_key(&format!("{}{}", key, FILE_SUFFIX)));
+        for (key, (content, layer)) in resolved {
+            vars.insert(key.clone(), content);
+            origins.insert(key, layer);
+        }
+    }
+}
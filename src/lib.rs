@@ -5,16 +5,33 @@
 mod test;
 #[cfg(feature = "derive")]
 pub mod derive;
+mod builder;
+mod schedule;
+mod source;
+mod dotenv;
+mod directory;
+mod file_ref;
 
-use crossbeam_channel::{Receiver, Sender};
+pub use builder::{ChannelPolicy, EnvironmentWatcherBuilder};
+use schedule::Schedule;
+use source::Layer;
+use file_ref::FileRefs;
+pub use source::{EnvSource, ProcessEnv};
+pub use dotenv::{parse_dotenv, DotEnvFile};
+pub use directory::DirectorySource;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
 use diff::Diff;
-use regex::Regex;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use regex::{Regex, RegexSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
-use spin_sleep::sleep;
-use log::{info, debug, trace};
+use log::{info, debug, trace, warn};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -30,18 +47,304 @@ pub enum Error {
 
     #[error("In current watcher exists subscribers.")]
     ReinitializedWithSubscribers,
+
+    /// The variable cannot be written to the environment
+    #[error("Invalid variable: {key:?}. Error: {error:?}")]
+    InvalidVariable { key: String, error: String },
+
+    /// The source of variables cannot be read
+    #[error("Failed to read env source {name:?}. Error: {error:?}")]
+    Source { name: String, error: String },
+
+    /// Invalid `.env` content, the line is counted from 1
+    #[error("Parse error at line {line}: {error}")]
+    Parse { line: usize, error: String },
+}
+
+/// Write to the process environment, applied by `EnvironmentWatcher::apply`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvUpdate {
+    /// Set the variable
+    Set(String, String),
+
+    /// Remove the variable
+    Remove(String),
+}
+
+impl EnvUpdate {
+    /// Name of the variable
+    pub fn key(&self) -> &str {
+        match self {
+            EnvUpdate::Set(key, _) | EnvUpdate::Remove(key) => key,
+        }
+    }
+
+    /// Check the update can be applied without panicking in `std::env`
+    fn validate(&self) -> Result<()> {
+        let key = self.key();
+        let invalid = |error: &str| {
+            Err(Error::InvalidVariable {
+                key: key.to_string(),
+                error: error.to_string(),
+            })
+        };
+        if key.is_empty() {
+            return invalid("empty key");
+        }
+        if key.contains('=') || key.contains('\0') {
+            return invalid("key contains '=' or NUL");
+        }
+        if let EnvUpdate::Set(_, value) = self {
+            if value.contains('\0') {
+                return invalid("value contains NUL");
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the update to the process environment, returns the new value
+    fn write(self) -> Option<String> {
+        match self {
+            EnvUpdate::Set(key, value) => {
+                std::env::set_var(&key, &value);
+                Some(value)
+            }
+            EnvUpdate::Remove(key) => {
+                std::env::remove_var(&key);
+                None
+            }
+        }
+    }
 }
 
-/// Changing the current state for a subscriber
-#[derive(Debug, Clone)]
+/// Changing the current state for a subscriber.
+/// Every change carries the version of the snapshot it belongs to and the time it has been detected.
+/// All changes found in one poll tick share the version, versions increase monotonically.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ChangeState {
-    /// Add or change state
-    Edit(String, String),
+    /// New variable
+    Added {
+        key: String,
+        value: String,
+        version: u64,
+        detected_at: SystemTime,
+    },
+
+    /// Changed value of an existing variable
+    Modified {
+        key: String,
+        old: String,
+        new: String,
+        version: u64,
+        detected_at: SystemTime,
+    },
+
+    /// Deleted variable with its last value
+    Removed {
+        key: String,
+        old: String,
+        version: u64,
+        detected_at: SystemTime,
+    },
+}
+
+impl ChangeState {
+    /// Name of the variable
+    pub fn key(&self) -> &str {
+        match self {
+            ChangeState::Added { key, .. } | ChangeState::Modified { key, .. } | ChangeState::Removed { key, .. } => key,
+        }
+    }
+
+    /// New value of the variable, `None` if it has been deleted
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            ChangeState::Added { value, .. } => Some(value),
+            ChangeState::Modified { new, .. } => Some(new),
+            ChangeState::Removed { .. } => None,
+        }
+    }
+
+    /// Previous value of the variable, `None` if it is new
+    pub fn old(&self) -> Option<&str> {
+        match self {
+            ChangeState::Added { .. } => None,
+            ChangeState::Modified { old, .. } | ChangeState::Removed { old, .. } => Some(old),
+        }
+    }
+
+    /// Version of the snapshot with this change
+    pub fn version(&self) -> u64 {
+        match self {
+            ChangeState::Added { version, .. } | ChangeState::Modified { version, .. } | ChangeState::Removed { version, .. } => {
+                *version
+            }
+        }
+    }
+
+    /// Time the change has been detected
+    pub fn detected_at(&self) -> SystemTime {
+        match self {
+            ChangeState::Added { detected_at, .. }
+            | ChangeState::Modified { detected_at, .. }
+            | ChangeState::Removed { detected_at, .. } => *detected_at,
+        }
+    }
+
+    /// Merge two consecutive changes of the same key into one, `None` if they cancel each other out.
+    /// The result goes from the value before `self` to the value after `next`.
+    fn coalesce(self, next: ChangeState) -> Option<ChangeState> {
+        let version = next.version();
+        let detected_at = next.detected_at();
+        let key = next.key().to_string();
+        match (self.old(), next.value()) {
+            (None, None) => None,
+            (None, Some(value)) => Some(ChangeState::Added {
+                key,
+                value: value.to_string(),
+                version,
+                detected_at,
+            }),
+            (Some(old), None) => Some(ChangeState::Removed {
+                key,
+                old: old.to_string(),
+                version,
+                detected_at,
+            }),
+            (Some(old), Some(new)) if old == new => None,
+            (Some(old), Some(new)) => Some(ChangeState::Modified {
+                key,
+                old: old.to_string(),
+                new: new.to_string(),
+                version,
+                detected_at,
+            }),
+        }
+    }
+}
+
+/// All changes found in one poll tick, ordered by key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeSet {
+    /// Changes sorted by key
+    changes: Vec<ChangeState>,
+
+    /// Version of the snapshot with these changes
+    version: u64,
+
+    /// Time the changes have been detected
+    detected_at: SystemTime,
+}
+
+impl ChangeSet {
+    /// Create a set of changes, sorted by key
+    fn new(mut changes: Vec<ChangeState>, version: u64, detected_at: SystemTime) -> Self {
+        changes.sort_by(|a, b| a.key().cmp(b.key()));
+        ChangeSet {
+            changes,
+            version,
+            detected_at,
+        }
+    }
+
+    /// Version of the snapshot with these changes
+    pub fn version(&self) -> u64 {
+        self.version
+    }
 
-    /// Delete key
-    Delete(String),
+    /// Time the changes have been detected
+    pub fn detected_at(&self) -> SystemTime {
+        self.detected_at
+    }
+
+    /// Changes sorted by key
+    pub fn changes(&self) -> &[ChangeState] {
+        &self.changes
+    }
+
+    /// Iterator over changes sorted by key
+    pub fn iter(&self) -> std::slice::Iter<'_, ChangeState> {
+        self.changes.iter()
+    }
+
+    /// Number of changes
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Whether the set has no changes
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl IntoIterator for ChangeSet {
+    type Item = ChangeState;
+    type IntoIter = std::vec::IntoIter<ChangeState>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.changes.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ChangeSet {
+    type Item = &'a ChangeState;
+    type IntoIter = std::slice::Iter<'a, ChangeState>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.changes.iter()
+    }
 }
 
+/// Predicate over key and value of a variable, used by `Subscribe::Filter`.
+/// The watcher always passes the value, `None` means the variable is not set.
+/// Two filters are equal only if they are clones of the same filter.
+#[derive(Clone)]
+pub struct KeyFilter(Arc<FilterFn>);
+
+/// Predicate of `KeyFilter`
+type FilterFn = dyn Fn(&str, Option<&str>) -> bool + Send + Sync;
+
+impl KeyFilter {
+    /// Create a filter from the predicate
+    /// let filter = KeyFilter::new(|k, v| k.ends_with("_URL") && v.is_some_and(|v| !v.is_empty()));
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&str, Option<&str>) -> bool + Send + Sync + 'static,
+    {
+        KeyFilter(Arc::new(filter))
+    }
+
+    /// Apply the predicate
+    pub fn matches(&self, key: &str, value: Option<&str>) -> bool {
+        (self.0)(key, value)
+    }
+}
+
+impl fmt::Debug for KeyFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyFilter(..)")
+    }
+}
+
+impl PartialEq for KeyFilter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for KeyFilter {}
+
+impl Hash for KeyFilter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (Arc::as_ptr(&self.0) as *const () as usize).hash(state);
+    }
+}
+
+/// Which variables the subscriber is interested in.
+///
+/// Each subscriber receives each change at most once per tick,
+/// regardless of how many of its keys or patterns match the changed variable.
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum Subscribe {
     /// Return all env variables
@@ -57,16 +360,476 @@ pub enum Subscribe {
     /// let v = vec!["my.project.*", "my.project2.*"];
     /// let subscribe = Subscribe::PatternEnvs(v);
     PatternEnvs(Vec<String>),
+
+    /// Subscribe by key prefix, compared literally.
+    /// Example by prefix:
+    /// let v = vec!["server.", "APP_"];
+    /// let subscribe = Subscribe::Prefix(v);
+    Prefix(Vec<String>),
+
+    /// Subscribe by glob, matched against the whole key.
+    /// `*` matches any sequence of characters, `?` matches exactly one character, everything else is literal.
+    /// Example by glob:
+    /// let v = vec!["APP_*", "my.project.*.host"];
+    /// let subscribe = Subscribe::Glob(v);
+    Glob(Vec<String>),
+
+    /// Subscribe by predicate over key and value.
+    /// The snapshot holds the variables whose value matches, changes follow the same rule:
+    /// a variable changed to a matching value arrives as `Added`, one changed to a rejected value or deleted - as `Removed`.
+    /// Example by filter:
+    /// let filter = KeyFilter::new(|k, v| k.ends_with("_URL") && v.is_some_and(|v| !v.is_empty()));
+    /// let subscribe = Subscribe::Filter(filter);
+    Filter(KeyFilter),
+
+    /// Subscribe by any of the nested subscriptions
+    AnyOf(Vec<Subscribe>),
+
+    /// Subscribe by keys matching all of the nested subscriptions.
+    /// An empty list matches every key.
+    AllOf(Vec<Subscribe>),
+
+    /// Subscribe by keys not matching the nested subscription
+    Not(Box<Subscribe>),
+
+    /// Subscribe by keys of `include` except keys of `exclude`.
+    /// Example all `APP_*` keys except secrets:
+    /// let subscribe = Subscribe::exclude(Subscribe::Glob(vec!["APP_*"]), Subscribe::Glob(vec!["APP_SECRET_*"]));
+    Exclude {
+        include: Box<Subscribe>,
+        exclude: Box<Subscribe>,
+    },
+}
+
+impl Subscribe {
+    /// Shortcut for `Subscribe::Exclude`
+    pub fn exclude(include: Subscribe, exclude: Subscribe) -> Self {
+        Subscribe::Exclude {
+            include: Box::new(include),
+            exclude: Box::new(exclude),
+        }
+    }
+}
+
+/// Compiled form of `Subscribe`, validated once at subscription time.
+enum Matcher {
+    /// Any key
+    All,
+
+    /// Exact keys
+    Envs(HashSet<String>),
+
+    /// Compiled patterns, a key matches if any pattern of the set matches
+    Patterns(RegexSet),
+
+    /// Literal prefixes
+    Prefix(Vec<String>),
+
+    /// Globs compiled into anchored patterns
+    Glob(RegexSet),
+
+    /// Predicate over key and value
+    Filter(KeyFilter),
+
+    /// Any of the nested matchers
+    AnyOf(Vec<Matcher>),
+
+    /// All of the nested matchers
+    AllOf(Vec<Matcher>),
+
+    /// Negation of the nested matcher
+    Not(Box<Matcher>),
+
+    /// First matcher without the second one
+    Exclude(Box<Matcher>, Box<Matcher>),
+}
+
+impl Matcher {
+    /// Validate and compile the subscription
+    fn compile(subscribe: &Subscribe) -> Result<Self> {
+        let matcher = match subscribe {
+            Subscribe::All => Matcher::All,
+            Subscribe::Envs(envs) => Matcher::Envs(envs.iter().cloned().collect()),
+            Subscribe::PatternEnvs(envs) => {
+                // Check patterns one by one, so the error points to the broken pattern
+                for pattern in envs.iter() {
+                    Regex::new(pattern).map_err(|e| Error::InvalidPattern {
+                        pattern: pattern.clone(),
+                        error: e.to_string(),
+                    })?;
+                }
+                let set = RegexSet::new(envs).map_err(|e| Error::InvalidPattern {
+                    pattern: envs.join(", "),
+                    error: e.to_string(),
+                })?;
+                Matcher::Patterns(set)
+            }
+            Subscribe::Prefix(prefixes) => Matcher::Prefix(prefixes.clone()),
+            Subscribe::Glob(globs) => {
+                let set = RegexSet::new(globs.iter().map(|glob| glob_to_regex(glob))).map_err(|e| {
+                    Error::InvalidPattern {
+                        pattern: globs.join(", "),
+                        error: e.to_string(),
+                    }
+                })?;
+                Matcher::Glob(set)
+            }
+            Subscribe::Filter(filter) => Matcher::Filter(filter.clone()),
+            Subscribe::AnyOf(subs) => Matcher::AnyOf(subs.iter().map(Matcher::compile).collect::<Result<_>>()?),
+            Subscribe::AllOf(subs) => Matcher::AllOf(subs.iter().map(Matcher::compile).collect::<Result<_>>()?),
+            Subscribe::Not(sub) => Matcher::Not(Box::new(Matcher::compile(sub)?)),
+            Subscribe::Exclude { include, exclude } => Matcher::Exclude(
+                Box::new(Matcher::compile(include)?),
+                Box::new(Matcher::compile(exclude)?),
+            ),
+        };
+        Ok(matcher)
+    }
+
+    /// Whether the key belongs to the subscription.
+    /// Overlapping keys or patterns still give a single answer, so a change is delivered once.
+    fn matches(&self, key: &str, value: Option<&str>) -> bool {
+        match self {
+            Matcher::All => true,
+            Matcher::Envs(envs) => envs.contains(key),
+            Matcher::Patterns(set) => set.is_match(key),
+            Matcher::Prefix(prefixes) => prefixes.iter().any(|prefix| key.starts_with(&**prefix)),
+            Matcher::Glob(set) => set.is_match(key),
+            Matcher::Filter(filter) => filter.matches(key, value),
+            Matcher::AnyOf(matchers) => matchers.iter().any(|m| m.matches(key, value)),
+            Matcher::AllOf(matchers) => matchers.iter().all(|m| m.matches(key, value)),
+            Matcher::Not(matcher) => !matcher.matches(key, value),
+            Matcher::Exclude(include, exclude) => include.matches(key, value) && !exclude.matches(key, value),
+        }
+    }
+
+    /// The change as seen by the subscriber, by the same rule as its snapshot, `None` if the variable stays outside.
+    /// A variable entering the subscription, e.g. changed to a value its filter accepts, is delivered as `Added`,
+    /// a variable leaving it - as `Removed` with the old value.
+    fn project(&self, change: &ChangeState) -> Option<ChangeState> {
+        let key = change.key();
+        let old = change.old().filter(|old| self.matches(key, Some(old)));
+        let new = change.value().filter(|new| self.matches(key, Some(new)));
+        let (version, detected_at) = (change.version(), change.detected_at());
+        match (old, new) {
+            (Some(_), Some(_)) => Some(change.clone()),
+            (Some(old), None) => Some(ChangeState::Removed {
+                key: key.to_string(),
+                old: old.to_string(),
+                version,
+                detected_at,
+            }),
+            (None, Some(new)) => Some(ChangeState::Added {
+                key: key.to_string(),
+                value: new.to_string(),
+                version,
+                detected_at,
+            }),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Translate the glob into an anchored regular expression
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    let mut literal = [0u8; 4];
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            _ => pattern.push_str(&regex::escape(c.encode_utf8(&mut literal))),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+/// Registered subscriber
+struct Subscriber {
+    /// Subscribe type
+    subscribe: Subscribe,
+
+    /// Compiled subscribe type
+    matcher: Matcher,
+
+    /// Sender for notification
+    tx: Delivery,
+}
+
+/// How changes are delivered to the subscriber
+enum Delivery {
+    /// Every change separately
+    Events(Sender<ChangeState>),
+
+    /// All changes of a tick as one `ChangeSet`
+    Batch(Sender<ChangeSet>),
+
+    /// Changes accumulated until no further changes arrive for `window`, as one `ChangeSet`
+    Debounced {
+        tx: Sender<ChangeSet>,
+        window: Duration,
+        pending: Option<Pending>,
+    },
+}
+
+/// Changes of a debounced subscriber waiting for the quiet window
+struct Pending {
+    /// Coalesced changes by key
+    changes: BTreeMap<String, ChangeState>,
+
+    /// Delivery time, if nothing else changes
+    deadline: Instant,
+
+    /// Version of the last change
+    version: u64,
+
+    /// Time the last change has been detected
+    detected_at: SystemTime,
+}
+
+impl Pending {
+    /// Add the change, merging it with the pending change of the same key
+    fn push(&mut self, change: ChangeState) {
+        let key = change.key().to_string();
+        let merged = match self.changes.remove(&key) {
+            Some(prev) => prev.coalesce(change),
+            None => Some(change),
+        };
+        if let Some(merged) = merged {
+            self.changes.insert(key, merged);
+        }
+    }
+}
+
+/// Registered senders with an index for dispatch.
+/// Exact keys are looked up in the index, only `All` and pattern subscribers are checked for every change.
+#[derive(Default)]
+struct Senders {
+    /// key - subscription id
+    /// value - subscriber, for notification
+    subscribers: HashMap<u64, Subscriber>,
+
+    /// key - variable name
+    /// value - ids of subscribers interested in exactly this key
+    by_key: HashMap<String, Vec<u64>>,
+
+    /// Ids of subscribers which have to be checked for every change
+    wildcard: Vec<u64>,
+}
+
+impl Senders {
+    /// Number of subscribers
+    fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Register the subscriber and index its keys
+    fn insert(&mut self, id: u64, subscriber: Subscriber) {
+        match &subscriber.matcher {
+            Matcher::Envs(envs) => {
+                for key in envs.iter() {
+                    self.by_key.entry(key.clone()).or_default().push(id);
+                }
+            }
+            _ => self.wildcard.push(id),
+        }
+        self.subscribers.insert(id, subscriber);
+    }
+
+    /// Remove the subscriber with its index entries
+    fn remove(&mut self, id: u64) -> Option<Subscriber> {
+        let subscriber = self.subscribers.remove(&id)?;
+        match &subscriber.matcher {
+            Matcher::Envs(envs) => {
+                for key in envs.iter() {
+                    if let Some(ids) = self.by_key.get_mut(key) {
+                        ids.retain(|i| *i != id);
+                        if ids.is_empty() {
+                            self.by_key.remove(key);
+                        }
+                    }
+                }
+            }
+            _ => self.wildcard.retain(|i| *i != id),
+        }
+        Some(subscriber)
+    }
+
+    /// Remove all subscribers, closing their channels
+    fn clear(&mut self) {
+        self.subscribers.clear();
+        self.by_key.clear();
+        self.wildcard.clear();
+    }
+
+    /// Send changes to the interested subscribers.
+    /// Batch subscribers get one `ChangeSet` with their changes, in the order of `changes`.
+    /// Subscribers with a dropped receiver are removed.
+    fn dispatch(&mut self, changes: &ChangeSet) {
+        let mut disconnected = HashSet::new();
+        let mut batches = HashMap::<u64, Vec<ChangeState>>::new();
+
+        for change in changes.iter() {
+            let key = change.key();
+            let indexed = self.by_key.get(key).into_iter().flatten();
+            for id in indexed.chain(self.wildcard.iter()) {
+                if disconnected.contains(id) {
+                    continue;
+                }
+                let subscriber = &self.subscribers[id];
+                let Some(change) = subscriber.matcher.project(change) else {
+                    continue;
+                };
+                match &subscriber.tx {
+                    Delivery::Events(tx) => {
+                        if !notify(*id, tx, change) {
+                            disconnected.insert(*id);
+                        }
+                    }
+                    Delivery::Batch(_) | Delivery::Debounced { .. } => batches.entry(*id).or_default().push(change),
+                }
+            }
+        }
+
+        let now = Instant::now();
+        for (id, batch) in batches {
+            match &mut self.subscribers.get_mut(&id).unwrap().tx {
+                Delivery::Batch(tx) => {
+                    let batch = ChangeSet {
+                        changes: batch,
+                        version: changes.version,
+                        detected_at: changes.detected_at,
+                    };
+                    if !notify(id, tx, batch) {
+                        disconnected.insert(id);
+                    }
+                }
+                Delivery::Debounced { window, pending, .. } => {
+                    let pending = pending.get_or_insert_with(|| Pending {
+                        changes: BTreeMap::new(),
+                        deadline: now,
+                        version: changes.version,
+                        detected_at: changes.detected_at,
+                    });
+                    batch.into_iter().for_each(|change| pending.push(change));
+                    pending.deadline = now + *window;
+                    pending.version = changes.version;
+                    pending.detected_at = changes.detected_at;
+                }
+                Delivery::Events(_) => {}
+            }
+        }
+
+        for id in disconnected {
+            self.remove(id);
+        }
+    }
+
+    /// Whether any debounced subscriber has changes waiting for its quiet window
+    fn has_pending(&self) -> bool {
+        self.subscribers.values().any(|subscriber| matches!(subscriber.tx, Delivery::Debounced { pending: Some(_), .. }))
+    }
+
+    /// Deliver pending changes of debounced subscribers whose quiet window has passed.
+    /// Returns the nearest deadline of the changes still waiting.
+    fn flush(&mut self, now: Instant) -> Option<Instant> {
+        let mut disconnected = Vec::new();
+        let mut next = None::<Instant>;
+
+        for (id, subscriber) in self.subscribers.iter_mut() {
+            if let Delivery::Debounced { tx, pending, .. } = &mut subscriber.tx {
+                match pending {
+                    Some(p) if p.deadline <= now => {
+                        let p = pending.take().unwrap();
+                        if p.changes.is_empty() {
+                            continue;
+                        }
+                        let set = ChangeSet {
+                            changes: p.changes.into_values().collect(),
+                            version: p.version,
+                            detected_at: p.detected_at,
+                        };
+                        if !notify(*id, tx, set) {
+                            disconnected.push(*id);
+                        }
+                    }
+                    Some(p) => next = Some(next.map_or(p.deadline, |n| n.min(p.deadline))),
+                    None => {}
+                }
+            }
+        }
+
+        for id in disconnected {
+            self.remove(id);
+        }
+        next
+    }
+}
+
+/// Handle of a live subscription.
+/// Holds the snapshot taken at the time of subscribing and the channel with changes:
+/// `ChangeState` for every change or `ChangeSet` for batch subscriptions.
+/// The subscription is removed from the watcher when the handle is dropped or `unsubscribe` is called.
+pub struct Subscription<T = ChangeState> {
+    /// Subscription id, used to find the sender on removal
+    id: u64,
+
+    /// Snapshot at the time of subscribing
+    data: HashMap<String, String>,
+
+    /// Version of the snapshot
+    version: u64,
+
+    /// Channel for receiving changes
+    rx: Receiver<T>,
+
+    /// Senders of the watcher
+    senders: Weak<Mutex<Senders>>,
+}
+
+impl<T> Subscription<T> {
+    /// Snapshot of the data at the time of subscribing
+    pub fn data(&self) -> &HashMap<String, String> {
+        &self.data
+    }
+
+    /// Version of the snapshot
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Channel for receiving changes
+    pub fn receiver(&self) -> &Receiver<T> {
+        &self.rx
+    }
+
+    /// Remove the subscription from the watcher. Same as dropping the handle.
+    pub fn unsubscribe(self) {}
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        if let Some(senders) = self.senders.upgrade() {
+            if let Some(subscriber) = senders.lock().unwrap().remove(self.id) {
+                debug!("Unsubscribe by {:?}", &subscriber.subscribe);
+            }
+        }
+    }
 }
 
 /// Baseline implementation for data.
 /// A separate thread listens for data changes through the channel, in case of data changes, we receive an event and change the data snapshot.
+/// All changes of one tick or transaction are applied to the snapshot at once, so it is never half-updated.
 pub struct EnvironmentData {
     /// Snapshot data
     data: Arc<Mutex<HashMap<String, String>>>,
 
-    /// Channel for receiving changes for a specific key
-    rx: Receiver<ChangeState>,
+    /// Version of the last applied change
+    version: Arc<AtomicU64>,
+
+    /// Subscription for receiving changes for a specific key
+    subscription: Subscription<ChangeSet>,
 }
 
 impl EnvironmentData {
@@ -80,261 +843,659 @@ impl EnvironmentData {
         Arc::clone(&self.data)
     }
 
+    /// Version of the last change applied to the snapshot
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
     /// In a separate thread, we listen to the change of variables
     pub fn receive(&self) {
         let snapshot = Arc::clone(&self.data);
-        let rx = self.rx.clone();
-
-        std::thread::spawn(move || loop {
-            let data = rx.recv().unwrap();
-            let mut snapshot = snapshot.lock().unwrap();
-            match data {
-                ChangeState::Edit(k, v) => {
-                    snapshot.insert(k.clone(), v.clone());
-                }
-                ChangeState::Delete(k) => {
-                    snapshot.remove(&*k);
+        let version = Arc::clone(&self.version);
+        let rx = self.subscription.receiver().clone();
+
+        // The loop ends once the watcher has been shut down and the channel is closed.
+        std::thread::spawn(move || {
+            while let Ok(changes) = rx.recv() {
+                let mut snapshot = snapshot.lock().unwrap();
+                version.fetch_max(changes.version(), Ordering::SeqCst);
+                for data in changes {
+                    match data {
+                        ChangeState::Added { key, value, .. } | ChangeState::Modified { key, new: value, .. } => {
+                            snapshot.insert(key, value);
+                        }
+                        ChangeState::Removed { key, .. } => {
+                            snapshot.remove(&*key);
+                        }
+                    };
                 }
-            };
+            }
         });
     }
 }
 
-/// The current state of the environment
-pub struct EnvironmentWatcher {
+/// State shared between the watcher and its polling thread
+struct Shared {
     /// Current env state
-    state: Arc<Mutex<HashMap<String, String>>>,
+    state: Mutex<HashMap<String, String>>,
 
     /// Sender list
-    /// key - subscribe type
-    /// value - sender list, for notification
-    senders: Arc<Mutex<HashMap<Subscribe, Vec<Sender<ChangeState>>>>>,
+    /// key - subscription id
+    /// value - subscriber, for notification
+    senders: Arc<Mutex<Senders>>,
+
+    /// Version of the current state, increased on every poll tick with changes
+    version: AtomicU64,
+
+    /// Only variables matching the filter are tracked, all if `None`
+    filter: Option<Matcher>,
+
+    /// Time of the last detected change
+    last_change: Mutex<Instant>,
+
+    /// Notified on every detected change
+    changed: Condvar,
+
+    /// When the polling thread scans next
+    schedule: Mutex<Schedule>,
+
+    /// Layers of variables, later layers override earlier ones
+    layers: Mutex<Vec<Layer>>,
+
+    /// Name of the layer supplying each tracked variable, updated with the state
+    origins: Mutex<HashMap<String, Arc<str>>>,
+
+    /// Resolver of `*_FILE` variables, if enabled
+    file_refs: Option<Mutex<FileRefs>>,
+}
 
-    /// reading environment variables
-    interval: Duration,
+impl Shared {
+    fn new(filter: Option<Matcher>, schedule: Schedule, layers: Vec<Layer>, resolve_files: bool) -> Self {
+        Self {
+            state: Mutex::new(Default::default()),
+            senders: Arc::new(Mutex::new(Senders::default())),
+            version: AtomicU64::new(0),
+            filter,
+            last_change: Mutex::new(Instant::now()),
+            changed: Condvar::new(),
+            schedule: Mutex::new(schedule),
+            layers: Mutex::new(layers),
+            origins: Mutex::new(HashMap::new()),
+            file_refs: resolve_files.then(Mutex::default),
+        }
+    }
+
+    /// Read the tracked variables of all layers, later layers override earlier ones.
+    /// Returns the effective values and the name of the layer supplying each of them.
+    fn scan(&self) -> (HashMap<String, String>, HashMap<String, Arc<str>>) {
+        let mut layers = self.layers.lock().unwrap();
+        layers.iter_mut().for_each(Layer::refresh);
+        self.merge(&layers)
+    }
+
+    /// Merge the last snapshots of the layers, later layers override earlier ones.
+    /// Returns the effective tracked values and the name of the layer supplying each of them.
+    fn merge(&self, layers: &[Layer]) -> (HashMap<String, String>, HashMap<String, Arc<str>>) {
+        let mut sys_data = HashMap::<String, String>::new();
+        // Index of the layer supplying each variable
+        let mut origins = HashMap::<String, usize>::new();
+        for (i, layer) in layers.iter().enumerate() {
+            if let Some(snapshot) = layer.cached() {
+                for (k, v) in snapshot.iter() {
+                    sys_data.insert(k.clone(), v.clone());
+                    origins.insert(k.clone(), i);
+                }
+            }
+        }
+        if let Some(file_refs) = &self.file_refs {
+            file_refs.lock().unwrap().resolve(&mut sys_data, &mut origins);
+        }
+        sys_data.retain(|k, v| self.tracks(k, v));
+        let origins = origins
+            .into_iter()
+            .filter(|(k, _)| sys_data.contains_key(k))
+            .map(|(k, i)| (k, Arc::clone(layers[i].name())))
+            .collect();
+        (sys_data, origins)
+    }
+
+    /// Whether the variable passes the filter of the watcher
+    fn tracks(&self, key: &str, value: &str) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.matches(key, Some(value)))
+    }
+
+    /// Read the environment, compare it with the current state and notify the subscribers.
+    /// Returns all changes found, the set is empty if nothing has changed.
+    fn poll(&self) -> ChangeSet {
+        let mut data_guard = self.state.lock().unwrap();
+        let mut subs_guard = self.senders.lock().unwrap();
+
+        let (sys_data, origins) = self.scan();
+        let changes = self.update(&mut data_guard, &mut subs_guard, sys_data, origins);
+        if !changes.is_empty() {
+            debug!("Find changes in environment.\nDiff {:?}", &changes);
+        }
+        changes
+    }
+
+    /// Compare the effective variables with the current state, make them the state and notify the subscribers.
+    /// Returns all changes found, the set is empty if nothing has changed.
+    fn update(
+        &self,
+        data_guard: &mut HashMap<String, String>,
+        subs_guard: &mut Senders,
+        sys_data: HashMap<String, String>,
+        origins: HashMap<String, Arc<str>>,
+    ) -> ChangeSet {
+        let mut changes = Vec::<ChangeState>::new();
+        let detected_at = SystemTime::now();
+        let mut version = self.version.load(Ordering::SeqCst);
+
+        if !sys_data.eq(data_guard) {
+            let different = data_guard.diff(&sys_data);
+            version += 1;
+
+            let remove_set = different.removed;
+            let altered = different.altered;
+
+            if !remove_set.is_empty() {
+                remove_set.iter().for_each(|k| {
+                    let remove = ChangeState::Removed {
+                        key: k.clone(),
+                        old: data_guard.get(k).cloned().unwrap_or_default(),
+                        version,
+                        detected_at,
+                    };
+                    changes.push(remove);
+                });
+            }
+
+            if !altered.is_empty() {
+                altered.keys().for_each(|k| {
+                    let new = sys_data.get(k).cloned().unwrap_or_default();
+                    let alter = match data_guard.get(k) {
+                        Some(old) => ChangeState::Modified {
+                            key: k.clone(),
+                            old: old.clone(),
+                            new,
+                            version,
+                            detected_at,
+                        },
+                        None => ChangeState::Added {
+                            key: k.clone(),
+                            value: new,
+                            version,
+                            detected_at,
+                        },
+                    };
+                    changes.push(alter);
+                });
+            }
+        };
+        *data_guard = sys_data;
+        *self.origins.lock().unwrap() = origins;
+
+        let changes = ChangeSet::new(changes, version, detected_at);
+        if !changes.is_empty() {
+            self.publish(subs_guard, &changes);
+        }
+        changes
+    }
+
+    /// Write the updates to the process environment and notify the subscribers without waiting for a poll.
+    /// The state lock is held for the whole write, so a concurrent poll never sees a half-applied batch.
+    /// The effective values are merged again from the last snapshots of the layers, with the writes recorded in the process layers:
+    /// a key defined by a later layer keeps its value and no event is sent, without a process layer nothing changes.
+    /// Layers never read yet, e.g. without preload, are read first, so the write is not lost until the next poll.
+    /// All changes share one version, several updates of the same key are coalesced.
+    fn apply(&self, updates: Vec<EnvUpdate>) -> Result<ChangeSet> {
+        for update in updates.iter() {
+            update.validate()?;
+        }
+
+        let mut data_guard = self.state.lock().unwrap();
+        let mut subs_guard = self.senders.lock().unwrap();
+        let mut layers = self.layers.lock().unwrap();
+
+        for layer in layers.iter_mut().filter(|layer| layer.cached().is_none()) {
+            layer.refresh();
+        }
+        for update in updates {
+            let key = update.key().to_string();
+            let value = update.write();
+            for layer in layers.iter_mut().filter(|layer| layer.is_process()) {
+                layer.write(&key, value.as_deref());
+            }
+        }
+        let (sys_data, origins) = self.merge(&layers);
+        drop(layers);
+
+        let changes = self.update(&mut data_guard, &mut subs_guard, sys_data, origins);
+        if !changes.is_empty() {
+            debug!("Apply changes to environment.\nDiff {:?}", &changes);
+        }
+        Ok(changes)
+    }
+
+    /// Make the changes the current version and notify the subscribers
+    fn publish(&self, subs_guard: &mut Senders, changes: &ChangeSet) {
+        self.version.store(changes.version, Ordering::SeqCst);
+        subs_guard.dispatch(changes);
+
+        *self.last_change.lock().unwrap() = Instant::now();
+        self.changed.notify_all();
+    }
+}
+
+/// Commands for the polling thread
+enum Command {
+    /// Stop polling and exit the thread
+    Shutdown,
+
+    /// Scan immediately, the changes are sent back once dispatched
+    Refresh(Sender<ChangeSet>),
+
+    /// The schedule has changed, recalculate the next poll
+    Reschedule,
+
+    /// Debounced changes are pending, recalculate the wake-up
+    Wake,
+}
+
+/// Handle to the running polling thread
+struct Worker {
+    /// Polling thread
+    handle: JoinHandle<()>,
+
+    /// Channel for controlling the polling thread
+    control: Sender<Command>,
+}
+
+/// Name of the polling thread, if not configured
+const DEFAULT_THREAD_NAME: &str = "env-watcher";
+
+/// The current state of the environment
+pub struct EnvironmentWatcher {
+    /// State shared with the polling thread
+    shared: Arc<Shared>,
+
+    /// Id for the next subscription
+    next_id: AtomicU64,
+
+    /// Name of the polling thread
+    thread_name: String,
+
+    /// Channels created for subscribers
+    channel: ChannelPolicy,
+
+    /// Never spawn the polling thread, changes are found by `poll_now`
+    manual: bool,
+
+    /// Polling thread, if started
+    worker: Mutex<Option<Worker>>,
 }
 
 impl EnvironmentWatcher {
-    /// Create a new instance to track the state
+    /// Create a new instance to track the state, preloaded and started.
     /// Interval - how often we request data and update the state (if required)
+    /// Same as `EnvironmentWatcher::builder().interval(interval).build()`.
     pub fn new(interval: Duration) -> Self {
-        info!("Starting env watcher with interval {:?}", &interval);
-        let env_state = Self {
-            state: Arc::new(Mutex::new(Default::default())),
-            senders: Arc::new(Mutex::new(Default::default())),
-            interval,
-        };
-        env_state.preload();
-        env_state.run();
-        env_state
+        Self::builder().interval(interval).start(None)
+    }
+
+    /// Builder for a watcher with explicit options
+    pub fn builder() -> EnvironmentWatcherBuilder {
+        EnvironmentWatcherBuilder::default()
     }
 
     /// Preload the environment
     fn preload(&self) {
-        let mut data = self.state.lock().unwrap();
-        std::env::vars().for_each(|kv| {
-            data.insert(kv.0, kv.1);
-        });
+        let mut data = self.shared.state.lock().unwrap();
+        let (sys_data, origins) = self.shared.scan();
+        *data = sys_data;
+        *self.shared.origins.lock().unwrap() = origins;
         trace!("Preload environment map:\n{:?}", &data)
     }
 
-    /// Subscribers size. (only `Subscribe`)
+    /// Name of the layer supplying the current value of the variable, `None` if it is not tracked.
+    /// A key written by `set` or `apply` comes from a `ProcessEnv` layer, unless a later layer defines it too.
+    pub fn origin(&self, key: &str) -> Option<String> {
+        self.shared.origins.lock().unwrap().get(key).map(|name| name.to_string())
+    }
+
+    /// Version of the current state.
+    /// Starts at 0 and is increased on every poll tick which found changes.
+    pub fn version(&self) -> u64 {
+        self.shared.version.load(Ordering::SeqCst)
+    }
+
+    /// Number of live subscriptions.
     pub fn size(&self) -> usize {
-        let size = self.senders.lock().unwrap().len();
+        let size = self.shared.senders.lock().unwrap().len();
         debug!("Current subscribers size: {:?}", &size);
         size
     }
 
-    /// Subscribe to the keys and get a snapshot of the data
+    /// Block until no changes have been detected for `window`, or `timeout` has elapsed.
+    /// Returns `true` if the environment is quiet, `false` on timeout.
+    /// Changes are detected by polling, so the precision is limited by the interval.
+    pub fn wait_for_quiescence(&self, window: Duration, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut last_change = self.shared.last_change.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let quiet_at = *last_change + window;
+            if now >= quiet_at {
+                return true;
+            }
+            if now >= deadline {
+                return false;
+            }
+            last_change = self
+                .shared
+                .changed
+                .wait_timeout(last_change, quiet_at.min(deadline) - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Subscribe to the keys and get a snapshot of the data.
+    /// The snapshot channel is always unbounded, whatever the `ChannelPolicy`: a dropped change would leave the snapshot stale forever.
     pub fn subscribe_snapshot(&self, subscribe: Subscribe) -> Result<EnvironmentData> {
-        let sub = self.subscribe(subscribe)?;
+        let sub = self.subscribe_with(subscribe, ChannelPolicy::Unbounded, Delivery::Batch)?;
         let data = EnvironmentData {
-            data: Arc::new(Mutex::new(sub.0)),
-            rx: sub.1,
+            data: Arc::new(Mutex::new(sub.data().clone())),
+            version: Arc::new(AtomicU64::new(sub.version())),
+            subscription: sub,
         };
         data.receive();
         Ok(data)
     }
 
-    /// We subscribe to the keys, if successful, we get a snapshot of the current data and a channel for updating this data
-    pub fn subscribe(
+    /// We subscribe to the keys, if successful, we get a snapshot of the current data and a channel for updating this data.
+    /// The subscription lives as long as the returned handle.
+    pub fn subscribe(&self, subscribe: Subscribe) -> Result<Subscription> {
+        self.subscribe_with(subscribe, self.channel, Delivery::Events)
+    }
+
+    /// Same as `subscribe`, but all changes of one poll tick relevant to the subscriber arrive as a single `ChangeSet`, sorted by key.
+    pub fn subscribe_batch(&self, subscribe: Subscribe) -> Result<Subscription<ChangeSet>> {
+        self.subscribe_with(subscribe, self.channel, Delivery::Batch)
+    }
+
+    /// Same as `subscribe_batch`, but changes are accumulated until no further relevant changes occur for `window`.
+    /// Then they are delivered as one coalesced `ChangeSet`: a variable changed several times appears once,
+    /// and changes which cancel each other out (e.g. added and removed again) are not delivered at all.
+    pub fn subscribe_debounced(&self, subscribe: Subscribe, window: Duration) -> Result<Subscription<ChangeSet>> {
+        self.subscribe_with(subscribe, self.channel, |tx| Delivery::Debounced {
+            tx,
+            window,
+            pending: None,
+        })
+    }
+
+    /// Subscribe with the given channel and delivery
+    fn subscribe_with<T>(
         &self,
         subscribe: Subscribe,
-    ) -> Result<(HashMap<String, String>, Receiver<ChangeState>)> {
+        channel: ChannelPolicy,
+        delivery: impl FnOnce(Sender<T>) -> Delivery,
+    ) -> Result<Subscription<T>> {
         debug!("Subscribe by {:?}", &subscribe);
-        let (tx, rx) = crossbeam_channel::unbounded::<ChangeState>();
+        let matcher = Matcher::compile(&subscribe)?;
+        let (tx, rx) = match channel {
+            ChannelPolicy::Unbounded => crossbeam_channel::unbounded::<T>(),
+            ChannelPolicy::Bounded(capacity) => crossbeam_channel::bounded::<T>(capacity),
+        };
 
-        let mut data = {
-            let state = self.state.lock();
+        let (mut data, version) = {
+            let state = self.shared.state.lock();
 
             let state_guard = state.unwrap();
 
-            state_guard.clone()
+            (state_guard.clone(), self.version())
         };
 
-        let sub = match &subscribe {
-            Subscribe::All => (data, rx),
+        data.retain(|k, v| matcher.matches(k, Some(v)));
 
-            Subscribe::Envs(envs) => {
-                data.retain(|k, _| envs.contains(k));
+        let id = self._subscribe(subscribe, matcher, delivery(tx));
+        Ok(Subscription {
+            id,
+            data,
+            version,
+            rx,
+            senders: Arc::downgrade(&self.shared.senders),
+        })
+    }
 
-                (data, rx)
-            }
+    /// Adding keys to the current state. Returns the id of the subscription.
+    fn _subscribe(&self, sub: Subscribe, matcher: Matcher, tx: Delivery) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let senders = self.shared.senders.lock();
+        let mut guard = senders.unwrap();
+        guard.insert(id, Subscriber { subscribe: sub, matcher, tx });
+        id
+    }
 
-            Subscribe::PatternEnvs(envs) => {
-                let envs = envs
-                    .iter()
-                    .map(|pattern| {
-                        Regex::new(&*pattern)
-                            .map_err(|e| Error::InvalidPattern {
-                                pattern: pattern.clone(),
-                                error: e.to_string(),
-                            })
-                            .unwrap()
-                    })
-                    .collect::<Vec<Regex>>();
-
-                data.retain(|k, _| {
-                    let mut find = false;
-                    for env in envs.iter() {
-                        match env.find(k) {
-                            None => {}
-                            Some(_) => {
-                                find = true;
-                            }
-                        }
+    /// In a separate thread, we process state changes at intervals.
+    /// If the values change, we will notify the subscribers who have subscribed to these values.
+    /// Calling `run` on an already running watcher or in manual mode does nothing.
+    pub fn run(&self) {
+        if self.manual {
+            debug!("Env watcher is in manual mode, polling thread is not started");
+            return;
+        }
+        let mut worker = self.worker.lock().unwrap();
+        if worker.is_some() {
+            debug!("Env watcher is already running");
+            return;
+        }
 
-                        if find {
-                            break;
-                        }
+        let shared = Arc::clone(&self.shared);
+        let (control, commands) = crossbeam_channel::bounded::<Command>(1);
+
+        let thread = std::thread::Builder::new().name(self.thread_name.clone());
+        let handle = thread.spawn(move || {
+            let mut last_poll = Instant::now();
+            let mut next_poll = last_poll;
+            loop {
+                if Instant::now() >= next_poll {
+                    let changes = shared.poll();
+                    last_poll = Instant::now();
+                    next_poll = last_poll + shared.schedule.lock().unwrap().next(!changes.is_empty());
+                }
+                // Wake up earlier if a debounced subscriber has to be notified
+                let wake_up = match shared.senders.lock().unwrap().flush(Instant::now()) {
+                    Some(deadline) => deadline.min(next_poll),
+                    None => next_poll,
+                };
+                match commands.recv_deadline(wake_up) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Ok(Command::Refresh(done)) => {
+                        let changes = shared.poll();
+                        last_poll = Instant::now();
+                        next_poll = last_poll + shared.schedule.lock().unwrap().next(!changes.is_empty());
+                        let _ = done.send(changes);
                     }
-                    find
-                });
+                    Ok(Command::Reschedule) => {
+                        next_poll = last_poll + shared.schedule.lock().unwrap().delay();
+                    }
+                    Ok(Command::Wake) => {}
+                    Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                        debug!("Env watcher polling thread stopped");
+                        break;
+                    }
+                }
+            }
+        });
+        let handle = handle.expect("failed to spawn env watcher thread");
+
+        *worker = Some(Worker { handle, control });
+    }
 
-                (data, rx)
+    /// Perform one scan-diff-dispatch cycle synchronously and return all changes found.
+    /// Subscribers are notified before the method returns, pending debounced changes whose window has passed are delivered too.
+    /// This is the way to drive a watcher in manual mode, but it can be called in any mode.
+    pub fn poll_now(&self) -> ChangeSet {
+        let changes = self.shared.poll();
+        self.shared.senders.lock().unwrap().flush(Instant::now());
+        changes
+    }
+
+    /// Set the variable in the process environment and notify the subscribers immediately.
+    /// Returns the changes delivered, empty if the value has not changed.
+    /// Before the first poll of a watcher without preload, the changes include every variable as added, like the first poll.
+    pub fn set(&self, key: impl Into<String>, value: impl Into<String>) -> Result<ChangeSet> {
+        self.apply(vec![EnvUpdate::Set(key.into(), value.into())])
+    }
+
+    /// Remove the variable from the process environment and notify the subscribers immediately.
+    /// Returns the changes delivered, empty if the variable did not exist.
+    pub fn remove(&self, key: impl Into<String>) -> Result<ChangeSet> {
+        self.apply(vec![EnvUpdate::Remove(key.into())])
+    }
+
+    /// Apply the updates in order to the process environment and notify the subscribers immediately.
+    /// The watcher is locked for the whole batch: the polling thread cannot observe it half-applied.
+    /// All changes share one version, several updates of the same key are delivered as one change.
+    /// Updates are validated before anything is written, an invalid key or value returns `Error::InvalidVariable`.
+    /// Without a `ProcessEnv` source the variables are written, but nothing changes for the watcher.
+    pub fn apply(&self, updates: impl IntoIterator<Item = EnvUpdate>) -> Result<ChangeSet> {
+        let changes = self.shared.apply(updates.into_iter().collect())?;
+        if !changes.is_empty() && self.shared.senders.lock().unwrap().has_pending() {
+            // The polling thread may sleep until the next poll, debounced changes are due earlier
+            if let Some(worker) = self.worker.lock().unwrap().as_ref() {
+                let _ = worker.control.try_send(Command::Wake);
             }
-        };
+        }
+        Ok(changes)
+    }
 
-        self._subscribe(subscribe.clone(), tx);
-        Ok(sub)
+    /// Start a transaction: several updates applied together by `Transaction::commit`.
+    /// let changes = watcher.transaction().set("DB_USER", "app").set("DB_PASSWORD", "secret").commit()?;
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            watcher: self,
+            updates: Vec::new(),
+        }
     }
 
-    /// Adding keys to the current state.
-    fn _subscribe(&self, sub: Subscribe, tx: Sender<ChangeState>) {
-        let senders = self.senders.lock();
-        let mut guard = senders.unwrap();
-        let entry = guard.entry(sub).or_insert_with(|| vec![]);
-        entry.push(tx);
+    /// Wake the polling thread to rescan immediately, e.g. after `std::env::set_var` in the current process.
+    /// Returns once the subscribers have been notified about the changes of that scan.
+    /// Without a running polling thread the scan is performed in the current thread, like `poll_now`.
+    pub fn refresh(&self) -> ChangeSet {
+        let control = self.worker.lock().unwrap().as_ref().map(|worker| worker.control.clone());
+        if let Some(control) = control {
+            let (done, changes) = crossbeam_channel::bounded(1);
+            if control.send(Command::Refresh(done)).is_ok() {
+                if let Ok(changes) = changes.recv() {
+                    return changes;
+                }
+            }
+            warn!("Env watcher polling thread is not responding, refreshing in the current thread");
+        }
+        self.poll_now()
     }
 
-    /// In a separate thread, we process state changes at intervals.
-    /// If the values change, we will notify the subscribers who have subscribed to these values.
-    pub fn run(&self) {
-        let data = Arc::clone(&self.state);
-        let subs = Arc::clone(&self.senders);
-        let interval = self.interval.clone();
+    /// Base polling interval
+    pub fn interval(&self) -> Duration {
+        self.shared.schedule.lock().unwrap().interval()
+    }
 
-        std::thread::spawn(move || loop {
-            {
-                let data = data.lock();
-                let mut data_guard = data.unwrap();
+    /// Change the polling interval at runtime, in adaptive mode this is the fastest rate and the backoff starts over.
+    /// The running polling thread is woken up, so the next poll happens one new interval after the previous one.
+    pub fn set_interval(&self, interval: Duration) {
+        info!("Changing env watcher interval to {:?}", interval);
+        self.shared.schedule.lock().unwrap().set_interval(interval);
+        if let Some(worker) = self.worker.lock().unwrap().as_ref() {
+            // A full channel means a command is pending, the thread recalculates the next poll anyway
+            let _ = worker.control.try_send(Command::Reschedule);
+        }
+    }
 
-                let subs = subs.lock();
-                let mut subs_guard = subs.unwrap();
+    /// Stop the polling thread and wait for it to finish.
+    /// All subscriber channels are closed, so receivers observe disconnection instead of waiting forever.
+    pub fn shutdown(&self) {
+        let worker = self.worker.lock().unwrap().take();
+        if let Some(worker) = worker {
+            info!("Shutting down env watcher");
+            let _ = worker.control.send(Command::Shutdown);
+            if worker.handle.join().is_err() {
+                warn!("Env watcher polling thread panicked");
+            }
+        }
+        self.shared.senders.lock().unwrap().clear();
+    }
+}
 
-                let mut sys_data = HashMap::<String, String>::new();
-                std::env::vars().for_each(|kv| {
-                    sys_data.insert(kv.0, kv.1);
-                });
+/// Updates of the environment applied atomically, created by `EnvironmentWatcher::transaction`.
+///
+/// Nothing is written until `commit`, dropping the transaction discards the updates.
+/// On commit every update is validated first, then all of them are written while the watcher is locked,
+/// so neither the polling thread nor other writers can observe a part of the transaction.
+/// Subscribers receive the changes with one version: batch subscribers and snapshots as a single `ChangeSet`,
+/// event subscribers as consecutive events.
+pub struct Transaction<'a> {
+    /// Watcher to apply the updates
+    watcher: &'a EnvironmentWatcher,
 
-                if !sys_data.eq(&data_guard) {
-                    let different = data_guard.diff(&sys_data);
+    /// Updates in order
+    updates: Vec<EnvUpdate>,
+}
 
-                    let mut changes = HashMap::<String, ChangeState>::new();
+impl Transaction<'_> {
+    /// Set the variable on commit
+    pub fn set(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.updates.push(EnvUpdate::Set(key.into(), value.into()));
+        self
+    }
 
-                    let remove_set = different.removed;
-                    let altered = different.altered;
+    /// Remove the variable on commit
+    pub fn remove(mut self, key: impl Into<String>) -> Self {
+        self.updates.push(EnvUpdate::Remove(key.into()));
+        self
+    }
 
-                    if !remove_set.is_empty() {
-                        remove_set.iter().for_each(|k| {
-                            let delete = ChangeState::Delete(k.clone());
-                            changes.insert(k.clone(), delete);
-                        });
-                    }
+    /// Updates collected so far
+    pub fn updates(&self) -> &[EnvUpdate] {
+        &self.updates
+    }
 
-                    if !altered.is_empty() {
-                        altered.iter().for_each(|k| {
-                            let alter =
-                                ChangeState::Edit(k.0.clone(), k.1.clone().unwrap_or_default());
-                            changes.insert(k.0.clone(), alter);
-                        });
-                    }
+    /// Apply all updates and notify the subscribers.
+    /// Returns `Error::InvalidVariable` without writing anything if any update is invalid.
+    pub fn commit(self) -> Result<ChangeSet> {
+        self.watcher.apply(self.updates)
+    }
+}
 
-                    if !changes.is_empty() {
-                        debug!("Find changes in environment.\nDiff {:?}", &changes);
-                        subs_guard.iter_mut().for_each(|s| {
-                            let sub = s.0;
-                            let senders = s.1;
-
-                            match sub {
-                                Subscribe::All => {
-                                    changes.iter().for_each(|change| {
-                                        senders.iter().for_each(|sender| {
-                                            sender.send(change.1.clone()).unwrap();
-                                        });
-                                    });
-                                }
-
-                                Subscribe::Envs(envs) => {
-                                    changes.iter().for_each(|change| {
-                                        if envs.contains(&change.0) {
-                                            senders.iter().for_each(|sender| {
-                                                sender.send(change.1.clone()).unwrap();
-                                            });
-                                        }
-                                    });
-                                }
-
-                                Subscribe::PatternEnvs(envs) => {
-                                    let envs = envs
-                                        .iter()
-                                        .map(|pattern| Regex::new(pattern).unwrap())
-                                        .collect::<Vec<Regex>>();
-
-                                    changes.iter().for_each(|change| {
-                                        envs.iter().for_each(|reg| {
-                                            let mat = reg.find(&*change.0);
-                                            match mat {
-                                                None => {}
-                                                Some(_) => {
-                                                    senders.iter().for_each(|sender| {
-                                                        sender.send(change.1.clone()).unwrap();
-                                                    });
-                                                }
-                                            }
-                                        });
-                                    });
-                                }
-                            }
-                        });
-                    }
-                };
-                *data_guard = sys_data;
-            }
-            sleep(interval);
-        });
+/// Send the change to the subscriber, never blocks.
+/// If the bounded channel of the subscriber is full, the change is dropped.
+/// Returns `false` if the receiver has been dropped, such subscriber is removed and the remaining subscribers keep receiving events.
+fn notify<T>(id: u64, sender: &Sender<T>, change: T) -> bool {
+    match sender.try_send(change) {
+        Ok(_) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!("Channel of subscription {} is full, change has been dropped", id);
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            warn!("Receiver of subscription {} has been dropped, removing subscription", id);
+            false
+        }
     }
 }
 
-/// Default instance with read interval 30 seconds.
+impl Drop for EnvironmentWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Default instance with read interval 500 milliseconds, preloaded and started.
+/// Same as `EnvironmentWatcher::builder().build()`.
 impl Default for EnvironmentWatcher {
     fn default() -> Self {
-        let env_state = Self {
-            state: Arc::new(Mutex::new(Default::default())),
-            senders: Arc::new(Mutex::new(HashMap::default())),
-            interval: Duration::from_millis(5 * 100),
-        };
-        env_state.run();
-        env_state
+        Self::builder().start(None)
     }
 }
@@ -0,0 +1,71 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// When the polling thread scans the environment next
+#[derive(Debug, Clone)]
+pub(crate) struct Schedule {
+    /// Base interval, the fastest rate in adaptive mode
+    interval: Duration,
+
+    /// Adaptive mode: the interval backs off up to this ceiling while the environment is quiet
+    ceiling: Option<Duration>,
+
+    /// Random deviation of every delay, a fraction of it in `0.0..=1.0`
+    jitter: f64,
+
+    /// Current delay without jitter
+    current: Duration,
+
+    /// State of the xorshift generator for jitter
+    seed: u64,
+}
+
+impl Schedule {
+    pub(crate) fn new(interval: Duration, ceiling: Option<Duration>, jitter: f64) -> Self {
+        // Seeded randomly per process, so processes started together do not poll in lockstep
+        let seed = RandomState::new().build_hasher().finish() | 1;
+        Self {
+            interval,
+            ceiling,
+            // NaN passes through `clamp` and would make `Duration::mul_f64` panic in the polling thread
+            jitter: if jitter.is_finite() { jitter.clamp(0.0, 1.0) } else { 0.0 },
+            current: interval,
+            seed,
+        }
+    }
+
+    /// Base interval
+    pub(crate) fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Change the base interval, the backoff starts over
+    pub(crate) fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+        self.current = interval;
+    }
+
+    /// Delay before the next poll, after a poll which found changes or not.
+    /// In adaptive mode a change resets the delay to the base interval, a quiet poll doubles it up to the ceiling.
+    pub(crate) fn next(&mut self, changed: bool) -> Duration {
+        self.current = match self.ceiling {
+            Some(ceiling) if !changed => self.current.saturating_mul(2).min(ceiling).max(self.interval),
+            _ => self.interval,
+        };
+        self.delay()
+    }
+
+    /// Current delay with jitter applied
+    pub(crate) fn delay(&mut self) -> Duration {
+        if self.jitter == 0.0 {
+            return self.current;
+        }
+        // xorshift64, uniform in [0, 1)
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let unit = (self.seed >> 11) as f64 / (1u64 << 53) as f64;
+        self.current.mul_f64(1.0 + self.jitter * (2.0 * unit - 1.0))
+    }
+}
@@ -0,0 +1,121 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::Arc;
+use log::{debug, warn};
+use crate::Result;
+
+/// Where the watcher reads variables from.
+/// The watcher takes a snapshot of every source on each poll and merges them, later sources override earlier ones.
+///
+/// struct Defaults;
+/// impl EnvSource for Defaults {
+///     fn name(&self) -> &str { "defaults" }
+///     fn snapshot(&mut self) -> Result<HashMap<String, String>> { Ok(HashMap::from([("PORT".to_string(), "8080".to_string())])) }
+///     fn changed(&mut self) -> bool { false }
+/// }
+pub trait EnvSource: Send {
+    /// Name of the source, for logs
+    fn name(&self) -> &str;
+
+    /// Read all variables of the source
+    fn snapshot(&mut self) -> Result<HashMap<String, String>>;
+
+    /// Change hint, asked before every poll except the first.
+    /// Returning `false` promises nothing has changed since the last snapshot, so the cached one is reused.
+    /// Default - `true`, a snapshot is taken on every poll.
+    fn changed(&mut self) -> bool {
+        true
+    }
+}
+
+/// Variables of the current process, `std::env::vars()`. The default source.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessEnv;
+
+impl EnvSource for ProcessEnv {
+    fn name(&self) -> &str {
+        "process"
+    }
+
+    fn snapshot(&mut self) -> Result<HashMap<String, String>> {
+        Ok(std::env::vars().collect())
+    }
+}
+
+/// Named source of the watcher with its last successful snapshot
+pub(crate) struct Layer {
+    /// Name of the layer, reported by `EnvironmentWatcher::origin`
+    name: Arc<str>,
+
+    /// The source is the process environment, written by `EnvironmentWatcher::apply`
+    process: bool,
+
+    /// Source of variables
+    source: Box<dyn EnvSource>,
+
+    /// Last successful snapshot, `None` before the first one
+    cache: Option<HashMap<String, String>>,
+
+    /// The last snapshot has failed, the error has been logged once
+    failing: bool,
+}
+
+impl Layer {
+    pub(crate) fn new<S: EnvSource + 'static>(name: impl Into<Arc<str>>, source: S) -> Self {
+        Self {
+            name: name.into(),
+            process: TypeId::of::<S>() == TypeId::of::<ProcessEnv>(),
+            source: Box::new(source),
+            cache: None,
+            failing: false,
+        }
+    }
+
+    /// Name of the layer
+    pub(crate) fn name(&self) -> &Arc<str> {
+        &self.name
+    }
+
+    /// Whether the layer reads the process environment
+    pub(crate) fn is_process(&self) -> bool {
+        self.process
+    }
+
+    /// Take a new snapshot of the source, unless it hints nothing has changed.
+    /// If the source fails, the last successful snapshot is kept.
+    /// The error is logged once until a snapshot succeeds again, a missing file is not reported on every poll.
+    pub(crate) fn refresh(&mut self) {
+        let changed = self.cache.is_none() || self.source.changed();
+        if changed {
+            match self.source.snapshot() {
+                Ok(snapshot) => {
+                    if self.failing {
+                        debug!("Env layer {:?} is readable again", self.name);
+                    }
+                    self.cache = Some(snapshot);
+                    self.failing = false;
+                }
+                Err(e) if self.failing => debug!("Failed to read env layer {:?} again. Error: {}", self.name, e),
+                Err(e) => {
+                    warn!("Failed to read env layer {:?}, using the last snapshot. Error: {}", self.name, e);
+                    self.failing = true;
+                }
+            }
+        }
+    }
+
+    /// Last successful snapshot, `None` if the source has never been read successfully
+    pub(crate) fn cached(&self) -> Option<&HashMap<String, String>> {
+        self.cache.as_ref()
+    }
+
+    /// Record a write to the process environment in the snapshot of a process layer
+    pub(crate) fn write(&mut self, key: &str, value: Option<&str>) {
+        if let Some(cache) = self.cache.as_mut() {
+            match value {
+                Some(value) => cache.insert(key.to_string(), value.to_string()),
+                None => cache.remove(key),
+            };
+        }
+    }
+}
@@ -1,21 +1,26 @@
 use std::env::set_var;
 use std::thread::sleep;
-use crate::{ChangeState, EnvironmentWatcher, Subscribe, init_env_watch, sub_env, sub_env_snapshot};
+use crate::schedule::Schedule;
+use crate::{notify, ChannelPolicy, DirectorySource, parse_dotenv, DotEnvFile, EnvSource, ProcessEnv, Result, ChangeSet, ChangeState, Delivery, EnvUpdate, EnvironmentWatcher, Error, KeyFilter, Matcher, Pending, Senders, Subscribe, Subscriber, init_env_watch, sub_env, sub_env_snapshot};
+use crossbeam_channel::Receiver;
+use std::collections::{BTreeMap, HashMap};
 use regex::Regex;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-static TEST_VALUE: &'static str = "ONLY_TEST";
+static TEST_VALUE: &str = "ONLY_TEST";
 
 fn fill_envs(envs: Vec<String>) {
     envs.iter().for_each(|k| {
-        std::env::set_var(k, &TEST_VALUE);
+        std::env::set_var(k, TEST_VALUE);
     });
 }
 
 #[test]
 pub fn create_all_subscriber() {
     let subscribe = Subscribe::All;
-    let vec_envs = vec!["my.test34.host", "my.test34.port", "my.test34.type"]
+    let vec_envs = ["my.test34.host", "my.test34.port", "my.test34.type"]
         .iter()
         .map(|v| v.to_string())
         .collect::<Vec<String>>();
@@ -24,7 +29,7 @@ pub fn create_all_subscriber() {
     let env_watcher = EnvironmentWatcher::new(Duration::from_secs(5));
     let data = env_watcher.subscribe(subscribe).unwrap();
 
-    let current_data = data.0;
+    let current_data = data.data();
 
     assert_eq!(
         Some(TEST_VALUE),
@@ -39,7 +44,7 @@ pub fn create_all_subscriber() {
         current_data.get("my.test34.type").map(|v| &**v)
     );
 
-    let tx = data.1;
+    let tx = data.receiver();
 
     std::env::remove_var("my.test34.host");
     std::env::set_var("my.test34.port", "2011");
@@ -49,27 +54,27 @@ pub fn create_all_subscriber() {
     loop {
         let state = tx.recv().unwrap();
         match state {
-            ChangeState::Edit(k, v) => {
-                println!("Change state. Edit: key - {}, value - {}", &*k, &*v);
+            ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
+                println!("Change state. Added or modified: key - {}, value - {}", &*k, &*v);
                 let key = &*k;
                 match key {
                     "my.test34.port" => {
                         assert_eq!("2011", &*v);
-                        i = i + 1;
+                        i += 1;
                     }
                     "my.test34.type" => {
                         assert_eq!("test", &*v);
-                        i = i + 1;
+                        i += 1;
                     }
                     _ => {
                         // Ignore
                     }
                 };
             }
-            ChangeState::Delete(k) => {
-                println!("Change state. Delete: key - {}", &*k);
+            ChangeState::Removed { key: k, .. } => {
+                println!("Change state. Removed: key - {}", &*k);
                 if k.eq("my.test34.host") {
-                    i = i + 1;
+                    i += 1;
                 }
             }
         }
@@ -81,7 +86,7 @@ pub fn create_all_subscriber() {
 
 #[test]
 pub fn create_envs_subscriber() {
-    let vec_envs = vec!["my.test.host", "my.test.port", "my.test.type"]
+    let vec_envs = ["my.test.host", "my.test.port", "my.test.type"]
         .iter()
         .map(|v| v.to_string())
         .collect::<Vec<String>>();
@@ -91,7 +96,7 @@ pub fn create_envs_subscriber() {
     let env_watcher = EnvironmentWatcher::new(Duration::from_secs(5));
     let data = env_watcher.subscribe(subscribe).unwrap();
 
-    let current_data = data.0;
+    let current_data = data.data();
     println!("{:?}", &current_data);
 
     assert_eq!(
@@ -107,7 +112,7 @@ pub fn create_envs_subscriber() {
         current_data.get("my.test.type").map(|v| &**v)
     );
 
-    let tx = data.1;
+    let tx = data.receiver();
 
     std::env::remove_var("my.test.host");
     std::env::set_var("my.test.port", "2011");
@@ -117,27 +122,27 @@ pub fn create_envs_subscriber() {
     loop {
         let state = tx.recv().unwrap();
         match state {
-            ChangeState::Edit(k, v) => {
-                println!("Change state. Edit: key - {}, value - {}", &*k, &*v);
+            ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
+                println!("Change state. Added or modified: key - {}, value - {}", &*k, &*v);
                 let key = &*k;
                 match key {
                     "my.test.port" => {
                         assert_eq!("2011", &*v);
-                        i = i + 1;
+                        i += 1;
                     }
                     "my.test.type" => {
                         assert_eq!("test", &*v);
-                        i = i + 1;
+                        i += 1;
                     }
                     _ => {
                         // Ignore
                     }
                 };
             }
-            ChangeState::Delete(k) => {
-                println!("Change state. Delete: key - {}", &*k);
+            ChangeState::Removed { key: k, .. } => {
+                println!("Change state. Removed: key - {}", &*k);
                 if k.eq("my.test.host") {
-                    i = i + 1;
+                    i += 1;
                 }
             }
         }
@@ -149,7 +154,7 @@ pub fn create_envs_subscriber() {
 
 #[test]
 pub fn create_pattern_envs_subscriber() {
-    let vec_second_envs = vec!["my.client.host", "my.client.port", "my.client.blob.size"]
+    let vec_second_envs = ["my.client.host", "my.client.port", "my.client.blob.size"]
         .iter()
         .map(|v| v.to_string())
         .collect::<Vec<String>>();
@@ -160,7 +165,7 @@ pub fn create_pattern_envs_subscriber() {
     let env_watcher = EnvironmentWatcher::new(Duration::from_secs(5));
     let data = env_watcher.subscribe(subscriber).unwrap();
 
-    let current_data = data.0;
+    let current_data = data.data();
     println!("{:?}", &current_data);
 
     assert_eq!(
@@ -176,7 +181,7 @@ pub fn create_pattern_envs_subscriber() {
         current_data.get("my.client.blob.size").map(|v| &**v)
     );
 
-    let tx = data.1;
+    let tx = data.receiver();
 
     std::env::remove_var("my.client.host");
     std::env::set_var("my.client.port", "2011");
@@ -186,27 +191,27 @@ pub fn create_pattern_envs_subscriber() {
     loop {
         let state = tx.recv().unwrap();
         match state {
-            ChangeState::Edit(k, v) => {
-                println!("Change state. Edit: key - {}, value - {}", &*k, &*v);
+            ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
+                println!("Change state. Added or modified: key - {}, value - {}", &*k, &*v);
                 let key = &*k;
                 match key {
                     "my.client.port" => {
                         assert_eq!("2011", &*v);
-                        i = i + 1;
+                        i += 1;
                     }
                     "my.client.blob.size" => {
                         assert_eq!("7MB", &*v);
-                        i = i + 1;
+                        i += 1;
                     }
                     _ => {
                         // Ignore
                     }
                 };
             }
-            ChangeState::Delete(k) => {
-                println!("Change state. Delete: key - {}", &*k);
+            ChangeState::Removed { key: k, .. } => {
+                println!("Change state. Removed: key - {}", &*k);
                 if k.eq("my.client.host") {
-                    i = i + 1;
+                    i += 1;
                 }
             }
         }
@@ -219,7 +224,7 @@ pub fn create_pattern_envs_subscriber() {
 #[test]
 pub fn snapshot_changes() {
     let subscribe = Subscribe::All;
-    let vec_envs = vec!["my.test44.host", "my.test44.port", "my.test44.type"]
+    let vec_envs = ["my.test44.host", "my.test44.port", "my.test44.type"]
         .iter()
         .map(|v| v.to_string())
         .collect::<Vec<String>>();
@@ -287,7 +292,9 @@ pub fn test_init_with_sub() -> crate::Result<()> {
     init_env_watch!(Duration::from_millis(500))?;
 
     let sub = Subscribe::Envs(vec!["test.west.key".to_string(), "test.west.key2".to_string()]);
-    let (data, rx) = sub_env!(sub.clone())?;
+    let subscription = sub_env!(sub.clone())?;
+    let data = subscription.data();
+    let rx = subscription.receiver();
     let snap = sub_env_snapshot!(sub)?;
 
     let west_key = data.get("test.west.key").map(|v| &**v);
@@ -313,23 +320,20 @@ pub fn test_init_with_sub() -> crate::Result<()> {
 
     let mut d = snap.data();
 
-    assert!(d.eq(&data));
+    assert!(d.eq(data));
 
     set_var("test.west.key", "derive");
 
     match rx.recv() {
         Ok(state) => {
             match state {
-                ChangeState::Edit(k, v) => {
-                    match &*k {
-                        "test.west.key" => {
-                            d.insert(k.clone(), v.clone());
-                            assert_eq!(&*v, "derive")
-                        }
-                        _ => {}
+                ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
+                    if &*k == "test.west.key" {
+                        d.insert(k.clone(), v.clone());
+                        assert_eq!(&*v, "derive")
                     }
                 }
-                ChangeState::Delete(_) => {}
+                ChangeState::Removed { .. } => {}
             }
         }
         Err(e) => {
@@ -348,9 +352,1280 @@ pub fn test_init_with_sub() -> crate::Result<()> {
         } else if x >= 15 {
             panic!("Awaiting data return err. Max attempt exceeded.");
         }
-        x = x + 1;
+        x += 1;
         sleep(Duration::from_millis(100));
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn shutdown_closes_subscribers() {
+    set_var("my.test55.host", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let subscription = env_watcher.subscribe(Subscribe::Envs(vec!["my.test55.host".to_string()])).unwrap();
+
+    env_watcher.shutdown();
+
+    assert!(subscription.receiver().recv_timeout(Duration::from_secs(1)).is_err());
+    assert_eq!(0, env_watcher.size());
+}
+
+#[test]
+pub fn drop_closes_subscribers() {
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let subscription = env_watcher.subscribe(Subscribe::All).unwrap();
+
+    drop(env_watcher);
+
+    assert!(subscription.receiver().recv_timeout(Duration::from_secs(1)).is_err());
+}
+
+#[test]
+pub fn unsubscribe_on_drop() {
+    let env_watcher = EnvironmentWatcher::new(Duration::from_secs(5));
+    let first = env_watcher.subscribe(Subscribe::All).unwrap();
+    let second = env_watcher.subscribe(Subscribe::All).unwrap();
+    let third = env_watcher.subscribe(Subscribe::Envs(vec!["my.test66.host".to_string()])).unwrap();
+    assert_eq!(3, env_watcher.size());
+
+    drop(first);
+    assert_eq!(2, env_watcher.size());
+
+    third.unsubscribe();
+    assert_eq!(1, env_watcher.size());
+
+    drop(second);
+    assert_eq!(0, env_watcher.size());
+}
+
+#[test]
+pub fn prune_disconnected_subscriber() {
+    set_var("my.test77.host", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let sub = Subscribe::Envs(vec!["my.test77.host".to_string()]);
+    let matcher = Matcher::compile(&sub).unwrap();
+    env_watcher._subscribe(sub, matcher, Delivery::Events(tx));
+    drop(rx);
+
+    let subscription = env_watcher.subscribe(Subscribe::Envs(vec!["my.test77.host".to_string()])).unwrap();
+    assert_eq!(2, env_watcher.size());
+
+    set_var("my.test77.host", "changed");
+
+    match subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap() {
+        ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
+            assert_eq!("my.test77.host", &*k);
+            assert_eq!("changed", &*v);
+        }
+        ChangeState::Removed { key: k, .. } => panic!("Unexpected delete of {}", k),
+    }
+    assert_eq!(1, env_watcher.size());
+}
+
+#[test]
+pub fn invalid_pattern_subscriber() {
+    let env_watcher = EnvironmentWatcher::new(Duration::from_secs(5));
+    let subscriber = Subscribe::PatternEnvs(vec!["^my.valid.*".to_string(), "my.(invalid".to_string()]);
+
+    match env_watcher.subscribe(subscriber) {
+        Err(Error::InvalidPattern { pattern, .. }) => assert_eq!("my.(invalid", &*pattern),
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Invalid pattern has been accepted"),
+    }
+    assert_eq!(0, env_watcher.size());
+}
+
+#[test]
+pub fn overlapping_subscriber_receives_once() {
+    set_var("my.test88.host", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let patterns = env_watcher
+        .subscribe(Subscribe::PatternEnvs(vec![
+            "^my.test88.*".to_string(),
+            "my.test88.host".to_string(),
+            ".*host$".to_string(),
+        ]))
+        .unwrap();
+    let envs = env_watcher
+        .subscribe(Subscribe::Envs(vec!["my.test88.host".to_string(), "my.test88.host".to_string()]))
+        .unwrap();
+
+    set_var("my.test88.host", "changed");
+
+    for subscription in [&patterns, &envs] {
+        let rx = subscription.receiver();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                ChangeState::Modified { key: k, new: v, .. } if &*k == "my.test88.host" => {
+                    assert_eq!("changed", &*v);
+                    break;
+                }
+                _ => {
+                    // Ignore
+                }
+            }
+        }
+    }
+
+    sleep(Duration::from_millis(500));
+
+    for subscription in [&patterns, &envs] {
+        let duplicate = subscription.receiver().try_iter().find(|state| state.key() == "my.test88.host");
+        assert!(duplicate.is_none(), "Change has been delivered twice");
+    }
+}
+
+fn added(key: &str, value: &str) -> ChangeState {
+    ChangeState::Added { key: key.to_string(), value: value.to_string(), version: 1, detected_at: SystemTime::UNIX_EPOCH }
+}
+
+fn modified(key: &str, old: &str, new: &str) -> ChangeState {
+    ChangeState::Modified {
+        key: key.to_string(),
+        old: old.to_string(),
+        new: new.to_string(),
+        version: 1,
+        detected_at: SystemTime::UNIX_EPOCH,
+    }
+}
+
+fn removed(key: &str, old: &str) -> ChangeState {
+    ChangeState::Removed { key: key.to_string(), old: old.to_string(), version: 1, detected_at: SystemTime::UNIX_EPOCH }
+}
+
+fn register(senders: &mut Senders, id: u64, subscribe: Subscribe) -> Receiver<ChangeState> {
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let matcher = Matcher::compile(&subscribe).unwrap();
+    senders.insert(id, Subscriber { subscribe, matcher, tx: Delivery::Events(tx) });
+    rx
+}
+
+#[test]
+pub fn dispatch_by_key_index() {
+    let mut senders = Senders::default();
+    let envs = register(&mut senders, 0, Subscribe::Envs(vec!["a".to_string(), "b".to_string(), "a".to_string()]));
+    let all = register(&mut senders, 1, Subscribe::All);
+    let pattern = register(&mut senders, 2, Subscribe::PatternEnvs(vec!["^c".to_string()]));
+
+    assert_eq!(vec![0], senders.by_key["a"]);
+    assert_eq!(vec![1, 2], senders.wildcard);
+
+    let changes = ChangeSet::new(vec![
+        added("a", "1"),
+        added("c", "2"),
+        removed("d", "3"),
+    ], 1, SystemTime::UNIX_EPOCH);
+    senders.dispatch(&changes);
+
+    assert_eq!(1, envs.try_iter().count());
+    assert_eq!(3, all.try_iter().count());
+    assert_eq!(1, pattern.try_iter().count());
+
+    senders.remove(0);
+    drop(pattern);
+    senders.dispatch(&changes);
+
+    assert!(senders.by_key.is_empty());
+    assert_eq!(vec![1], senders.wildcard);
+    assert_eq!(1, senders.len());
+}
+
+#[test]
+pub fn prefix_and_glob_matcher() {
+    let prefix = Matcher::compile(&Subscribe::Prefix(vec!["my.project.".to_string()])).unwrap();
+    assert!(prefix.matches("my.project.host", None));
+    assert!(!prefix.matches("my_project_host", None));
+    assert!(!prefix.matches("xmy.project.host", None));
+
+    let glob = Matcher::compile(&Subscribe::Glob(vec!["APP_*".to_string(), "my.project.?.host".to_string()])).unwrap();
+    assert!(glob.matches("APP_PORT", None));
+    assert!(glob.matches("APP_", None));
+    assert!(!glob.matches("XAPP_PORT", None));
+    assert!(glob.matches("my.project.1.host", None));
+    assert!(!glob.matches("my.project.12.host", None));
+    assert!(!glob.matches("my_project_1_host", None));
+    assert!(!glob.matches("my.project.1.hostname", None));
+}
+
+#[test]
+pub fn create_prefix_and_glob_subscriber() {
+    set_var("my.test99.host", TEST_VALUE);
+    set_var("xmy.test99.host", TEST_VALUE);
+    set_var("APP99_PORT", TEST_VALUE);
+    set_var("XAPP99_PORT", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let prefix = env_watcher.subscribe(Subscribe::Prefix(vec!["my.test99.".to_string()])).unwrap();
+    let glob = env_watcher.subscribe(Subscribe::Glob(vec!["APP99_*".to_string()])).unwrap();
+
+    assert_eq!(vec!["my.test99.host"], prefix.data().keys().collect::<Vec<&String>>());
+    assert_eq!(vec!["APP99_PORT"], glob.data().keys().collect::<Vec<&String>>());
+
+    set_var("xmy.test99.host", "changed");
+    set_var("XAPP99_PORT", "changed");
+    set_var("my.test99.host", "changed");
+    set_var("APP99_PORT", "changed");
+
+    for (subscription, key) in [(&prefix, "my.test99.host"), (&glob, "APP99_PORT")] {
+        match subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap() {
+            ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
+                assert_eq!(key, &*k);
+                assert_eq!("changed", &*v);
+            }
+            ChangeState::Removed { key: k, .. } => panic!("Unexpected delete of {}", k),
+        }
+        assert!(subscription.receiver().try_recv().is_err());
+    }
+}
+
+#[test]
+pub fn create_filter_subscriber() {
+    set_var("MY100_URL", "http://localhost");
+    set_var("MY100_PORT_URL", "");
+    set_var("MY100_HOST", "localhost");
+
+    let filter = KeyFilter::new(|k, v| k.starts_with("MY100_") && k.ends_with("_URL") && v.is_some_and(|v| !v.is_empty()));
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let subscription = env_watcher.subscribe(Subscribe::Filter(filter.clone())).unwrap();
+    let snapshot = env_watcher.subscribe_snapshot(Subscribe::Filter(filter)).unwrap();
+
+    assert_eq!(vec!["MY100_URL"], subscription.data().keys().collect::<Vec<&String>>());
+
+    set_var("MY100_HOST", "changed");
+    std::env::remove_var("MY100_URL");
+    set_var("MY100_PORT_URL", "http://localhost:8080");
+
+    // The removed key matched with its old value, so it leaves the subscriber's view
+    let mut states = HashMap::new();
+    while states.len() < 2 {
+        let state = subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap();
+        states.insert(state.key().to_string(), state);
+    }
+    assert!(matches!(&states["MY100_URL"], ChangeState::Removed { old, .. } if old == "http://localhost"));
+    // The empty value was outside the filter, so the key enters the subscriber's view
+    assert!(matches!(&states["MY100_PORT_URL"], ChangeState::Added { value, .. } if value == "http://localhost:8080"));
+    sleep(Duration::from_millis(300));
+    assert!(subscription.receiver().try_recv().is_err());
+
+    let data = snapshot.data();
+    assert_eq!(Some(&"http://localhost:8080".to_string()), data.get("MY100_PORT_URL"));
+    assert!(!data.contains_key("MY100_URL"));
+}
+
+#[test]
+pub fn filter_projects_changes() {
+    let mut senders = Senders::default();
+    let non_empty = KeyFilter::new(|_, v| v.is_some_and(|v| !v.is_empty()));
+    let filter = register(&mut senders, 0, Subscribe::Filter(non_empty));
+    let secret = KeyFilter::new(|_, v| v == Some("secret"));
+    let exclude = register(&mut senders, 1, Subscribe::exclude(Subscribe::All, Subscribe::Filter(secret)));
+
+    let changes = ChangeSet::new(vec![
+        modified("a", "http://x", ""),
+        modified("b", "", "http://y"),
+        modified("c", "x", "secret"),
+        modified("d", "secret", "y"),
+        modified("e", "", " "),
+    ], 1, SystemTime::UNIX_EPOCH);
+    senders.dispatch(&changes);
+
+    assert_eq!(
+        vec![removed("a", "http://x"), added("b", "http://y"), modified("c", "x", "secret"), modified("d", "secret", "y"), added("e", " ")],
+        filter.try_iter().collect::<Vec<ChangeState>>()
+    );
+    assert_eq!(
+        vec![modified("a", "http://x", ""), modified("b", "", "http://y"), removed("c", "x"), added("d", "y"), modified("e", "", " ")],
+        exclude.try_iter().collect::<Vec<ChangeState>>()
+    );
+}
+
+#[test]
+pub fn snapshot_value_leaves_filter() {
+    set_var("PRB102_URL", "http://x");
+    set_var("PRB102_HOST", "localhost");
+
+    let filter = KeyFilter::new(|k, v| k.starts_with("PRB102_") && v.is_some_and(|v| !v.is_empty()));
+    let env_watcher = EnvironmentWatcher::builder().manual(true).build().unwrap();
+    let snapshot = env_watcher.subscribe_snapshot(Subscribe::Filter(filter.clone())).unwrap();
+
+    set_var("PRB102_URL", "");
+    set_var("PRB102_HOST", "");
+    let changes = env_watcher.poll_now();
+
+    let mut x = 0;
+    while snapshot.version() < changes.version() {
+        assert!(x < 15, "Snapshot version has not been updated");
+        x += 1;
+        sleep(Duration::from_millis(100));
+    }
+    let fresh = env_watcher.subscribe(Subscribe::Filter(filter)).unwrap();
+    assert!(fresh.data().is_empty());
+    assert_eq!(fresh.data(), &snapshot.data());
+}
+
+#[test]
+pub fn composite_matcher() {
+    let app = Subscribe::Glob(vec!["APP_*".to_string()]);
+    let secret = Subscribe::Prefix(vec!["APP_SECRET_".to_string()]);
+
+    let exclude = Matcher::compile(&Subscribe::exclude(app.clone(), secret.clone())).unwrap();
+    assert!(exclude.matches("APP_PORT", None));
+    assert!(!exclude.matches("APP_SECRET_TOKEN", None));
+    assert!(!exclude.matches("DB_PORT", None));
+
+    let all_of = Matcher::compile(&Subscribe::AllOf(vec![app.clone(), Subscribe::Not(Box::new(secret.clone()))])).unwrap();
+    assert!(all_of.matches("APP_PORT", None));
+    assert!(!all_of.matches("APP_SECRET_TOKEN", None));
+
+    let any_of = Matcher::compile(&Subscribe::AnyOf(vec![secret, Subscribe::Envs(vec!["DB_PORT".to_string()])])).unwrap();
+    assert!(any_of.matches("APP_SECRET_TOKEN", None));
+    assert!(any_of.matches("DB_PORT", None));
+    assert!(!any_of.matches("APP_PORT", None));
+
+    let invalid = Subscribe::AnyOf(vec![app, Subscribe::PatternEnvs(vec!["(".to_string()])]);
+    assert!(Matcher::compile(&invalid).is_err());
+}
+
+#[test]
+pub fn create_exclude_subscriber() {
+    set_var("APP101_PORT", TEST_VALUE);
+    set_var("APP101_SECRET_TOKEN", TEST_VALUE);
+
+    let subscribe = Subscribe::exclude(
+        Subscribe::Glob(vec!["APP101_*".to_string()]),
+        Subscribe::Glob(vec!["APP101_SECRET_*".to_string()]),
+    );
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let subscription = env_watcher.subscribe(subscribe).unwrap();
+
+    assert_eq!(vec!["APP101_PORT"], subscription.data().keys().collect::<Vec<&String>>());
+
+    set_var("APP101_SECRET_TOKEN", "changed");
+    set_var("APP101_PORT", "changed");
+
+    match subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap() {
+        ChangeState::Added { key: k, value: v, .. } | ChangeState::Modified { key: k, new: v, .. } => {
+            assert_eq!("APP101_PORT", &*k);
+            assert_eq!("changed", &*v);
+        }
+        ChangeState::Removed { key: k, .. } => panic!("Unexpected delete of {}", k),
+    }
+    sleep(Duration::from_millis(300));
+    assert!(subscription.receiver().try_recv().is_err());
+}
+
+#[test]
+pub fn added_modified_removed_states() {
+    set_var("my.test111.port", "8080");
+    set_var("my.test111.host", "localhost");
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let subscription = env_watcher.subscribe(Subscribe::Prefix(vec!["my.test111.".to_string()])).unwrap();
+
+    set_var("my.test111.tls", "true");
+    set_var("my.test111.port", "8443");
+    std::env::remove_var("my.test111.host");
+
+    let mut states = HashMap::new();
+    while states.len() < 3 {
+        let state = subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap();
+        states.insert(state.key().to_string(), state);
+    }
+
+    match &states["my.test111.tls"] {
+        ChangeState::Added { value, .. } => assert_eq!("true", value),
+        state => panic!("Unexpected state {:?}", state),
+    }
+    match &states["my.test111.port"] {
+        ChangeState::Modified { old, new, .. } => {
+            assert_eq!("8080", old);
+            assert_eq!("8443", new);
+        }
+        state => panic!("Unexpected state {:?}", state),
+    }
+    match &states["my.test111.host"] {
+        ChangeState::Removed { old, .. } => assert_eq!("localhost", old),
+        state => panic!("Unexpected state {:?}", state),
+    }
+}
+
+#[test]
+pub fn dispatch_batch() {
+    let mut senders = Senders::default();
+    let (tx, batch) = crossbeam_channel::unbounded();
+    let subscribe = Subscribe::Prefix(vec!["server.".to_string()]);
+    let matcher = Matcher::compile(&subscribe).unwrap();
+    senders.insert(0, Subscriber { subscribe, matcher, tx: Delivery::Batch(tx) });
+
+    let changes = ChangeSet::new(vec![
+        added("server.tls", "true"),
+        added("client.port", "80"),
+        removed("server.address", "localhost"),
+        modified("server.port", "80", "443"),
+    ], 1, SystemTime::UNIX_EPOCH);
+    senders.dispatch(&changes);
+    senders.dispatch(&ChangeSet::new(vec![added("client.host", "localhost")], 2, SystemTime::UNIX_EPOCH));
+
+    let sets = batch.try_iter().collect::<Vec<ChangeSet>>();
+    assert_eq!(1, sets.len());
+    let keys = sets[0].iter().map(ChangeState::key).collect::<Vec<&str>>();
+    assert_eq!(vec!["server.address", "server.port", "server.tls"], keys);
+}
+
+#[test]
+pub fn create_batch_subscriber() {
+    set_var("my.test121.host", TEST_VALUE);
+    set_var("my.test121.port", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_secs(1));
+    let subscription = env_watcher.subscribe_batch(Subscribe::Prefix(vec!["my.test121.".to_string()])).unwrap();
+    assert_eq!(2, subscription.data().len());
+
+    // The first tick has already passed, the next one sees all changes at once.
+    sleep(Duration::from_millis(200));
+    set_var("my.test121.type", TEST_VALUE);
+    set_var("my.test121.port", "2011");
+    std::env::remove_var("my.test121.host");
+
+    let set = subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap();
+    let keys = set.iter().map(ChangeState::key).collect::<Vec<&str>>();
+    assert_eq!(vec!["my.test121.host", "my.test121.port", "my.test121.type"], keys);
+}
+
+#[test]
+pub fn versioned_changes() {
+    set_var("my.test131.host", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(100));
+    let subscription = env_watcher.subscribe(Subscribe::Envs(vec!["my.test131.host".to_string()])).unwrap();
+    let snapshot = env_watcher.subscribe_snapshot(Subscribe::Envs(vec!["my.test131.host".to_string()])).unwrap();
+    let subscribed_at = subscription.version();
+    assert!(subscribed_at <= env_watcher.version());
+
+    let before = SystemTime::now();
+    set_var("my.test131.host", "first");
+    let first = subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap();
+    set_var("my.test131.host", "second");
+    let second = subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap();
+
+    assert!(first.version() > subscribed_at);
+    assert!(second.version() > first.version());
+    assert!(first.detected_at() >= before);
+    assert!(second.detected_at() >= first.detected_at());
+    assert!(env_watcher.version() >= second.version());
+
+    let mut x = 0;
+    while snapshot.version() < second.version() {
+        assert!(x < 15, "Snapshot version has not been updated");
+        x += 1;
+        sleep(Duration::from_millis(100));
+    }
+    assert_eq!(Some("second"), snapshot.data().get("my.test131.host").map(|v| &**v));
+}
+
+#[test]
+pub fn coalesce_pending_changes() {
+    let mut pending = Pending {
+        changes: BTreeMap::new(),
+        deadline: Instant::now(),
+        version: 1,
+        detected_at: SystemTime::UNIX_EPOCH,
+    };
+
+    pending.push(added("new", "1"));
+    pending.push(modified("new", "1", "2"));
+    pending.push(modified("port", "80", "8080"));
+    pending.push(modified("port", "8080", "443"));
+    pending.push(modified("host", "a", "b"));
+    pending.push(modified("host", "b", "a"));
+    pending.push(added("tmp", "1"));
+    pending.push(removed("tmp", "1"));
+    pending.push(removed("tls", "true"));
+    pending.push(added("tls", "false"));
+    pending.push(modified("user", "root", "admin"));
+    pending.push(removed("user", "admin"));
+
+    let changes = pending.changes.into_values().collect::<Vec<ChangeState>>();
+    assert_eq!(
+        vec![
+            added("new", "2"),
+            modified("port", "80", "443"),
+            modified("tls", "true", "false"),
+            removed("user", "root"),
+        ],
+        changes
+    );
+}
+
+#[test]
+pub fn create_debounced_subscriber() {
+    set_var("my.test141.host", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(50));
+    let subscription = env_watcher
+        .subscribe_debounced(Subscribe::Prefix(vec!["my.test141.".to_string()]), Duration::from_millis(400))
+        .unwrap();
+
+    set_var("my.test141.host", "first");
+    sleep(Duration::from_millis(150));
+    set_var("my.test141.port", "2011");
+    sleep(Duration::from_millis(150));
+    set_var("my.test141.host", "second");
+
+    let set = subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap();
+    let keys = set.iter().map(ChangeState::key).collect::<Vec<&str>>();
+    assert_eq!(vec!["my.test141.host", "my.test141.port"], keys);
+    match &set.changes()[0] {
+        ChangeState::Modified { old, new, .. } => {
+            assert_eq!(TEST_VALUE, old);
+            assert_eq!("second", new);
+        }
+        state => panic!("Unexpected state {:?}", state),
+    }
+    assert!(subscription.receiver().recv_timeout(Duration::from_millis(600)).is_err());
+}
+
+#[test]
+pub fn wait_for_quiescence() {
+    let env_watcher = EnvironmentWatcher::new(Duration::from_millis(50));
+    assert!(env_watcher.wait_for_quiescence(Duration::from_millis(10), Duration::from_secs(1)));
+
+    let writer = std::thread::spawn(|| {
+        for i in 0..5 {
+            set_var("my.test142.host", i.to_string());
+            sleep(Duration::from_millis(100));
+        }
+    });
+    sleep(Duration::from_millis(150));
+    assert!(!env_watcher.wait_for_quiescence(Duration::from_millis(300), Duration::from_millis(200)));
+    assert!(env_watcher.wait_for_quiescence(Duration::from_millis(300), Duration::from_secs(5)));
+    writer.join().unwrap();
+}
+
+#[test]
+pub fn builder_options() {
+    set_var("my.test151.host", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::builder()
+        .interval(Duration::from_millis(100))
+        .preload(false)
+        .autostart(false)
+        .thread_name("test-watcher")
+        .filter(Subscribe::Prefix(vec!["my.test151.".to_string()]))
+        .build()
+        .unwrap();
+    let subscription = env_watcher.subscribe(Subscribe::All).unwrap();
+    assert!(subscription.data().is_empty());
+
+    // Not started yet
+    assert!(subscription.receiver().recv_timeout(Duration::from_millis(300)).is_err());
+
+    env_watcher.run();
+    match subscription.receiver().recv_timeout(Duration::from_secs(5)).unwrap() {
+        ChangeState::Added { key, value, .. } => {
+            assert_eq!("my.test151.host", key);
+            assert_eq!(TEST_VALUE, value);
+        }
+        state => panic!("Unexpected state {:?}", state),
+    }
+    assert!(subscription.receiver().recv_timeout(Duration::from_millis(300)).is_err());
+
+    let invalid = EnvironmentWatcher::builder().filter(Subscribe::PatternEnvs(vec!["(".to_string()])).build();
+    assert!(matches!(invalid, Err(Error::InvalidPattern { .. })));
+}
+
+#[test]
+pub fn notify_bounded_channel() {
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    assert!(notify(0, &tx, 1));
+    assert!(notify(0, &tx, 2));
+    assert_eq!(vec![1], rx.try_iter().collect::<Vec<i32>>());
+
+    drop(rx);
+    assert!(!notify(0, &tx, 3));
+}
+
+#[test]
+pub fn manual_polling() {
+    set_var("my.test161.host", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::builder()
+        .manual(true)
+        .filter(Subscribe::Prefix(vec!["my.test161.".to_string()]))
+        .build()
+        .unwrap();
+    env_watcher.run();
+    let subscription = env_watcher.subscribe(Subscribe::All).unwrap();
+    assert!(env_watcher.poll_now().is_empty());
+
+    set_var("my.test161.host", "changed");
+    set_var("my.test161.port", "2011");
+    sleep(Duration::from_millis(200));
+    assert!(subscription.receiver().try_recv().is_err());
+
+    let changes = env_watcher.poll_now();
+    let keys = changes.iter().map(ChangeState::key).collect::<Vec<&str>>();
+    assert_eq!(vec!["my.test161.host", "my.test161.port"], keys);
+    assert_eq!(1, changes.version());
+    assert_eq!(2, subscription.receiver().try_iter().count());
+
+    assert!(env_watcher.poll_now().is_empty());
+    assert_eq!(1, env_watcher.version());
+}
+
+#[test]
+pub fn refresh_after_local_write() {
+    set_var("my.test171.host", TEST_VALUE);
+
+    let env_watcher = EnvironmentWatcher::builder()
+        .interval(Duration::from_secs(30))
+        .filter(Subscribe::Prefix(vec!["my.test171.".to_string()]))
+        .build()
+        .unwrap();
+    let subscription = env_watcher.subscribe(Subscribe::All).unwrap();
+    // Let the first tick pass
+    sleep(Duration::from_millis(200));
+
+    set_var("my.test171.host", "changed");
+    let changes = env_watcher.refresh();
+
+    assert_eq!(vec!["my.test171.host"], changes.iter().map(ChangeState::key).collect::<Vec<&str>>());
+    match subscription.receiver().try_recv().unwrap() {
+        ChangeState::Modified { key, new, .. } => {
+            assert_eq!("my.test171.host", key);
+            assert_eq!("changed", new);
+        }
+        state => panic!("Unexpected state {:?}", state),
+    }
+
+    env_watcher.shutdown();
+    set_var("my.test171.host", "after shutdown");
+    assert_eq!(1, env_watcher.refresh().len());
+}
+
+#[test]
+pub fn write_through() {
+    let env_watcher = EnvironmentWatcher::builder()
+        .interval(Duration::from_secs(30))
+        .filter(Subscribe::Prefix(vec!["my.test181.".to_string()]))
+        .build()
+        .unwrap();
+    let subscription = env_watcher.subscribe(Subscribe::All).unwrap();
+
+    let changes = env_watcher.set("my.test181.host", "localhost").unwrap();
+    assert_eq!(1, changes.len());
+    assert_eq!(Some("localhost".to_string()), std::env::var("my.test181.host").ok());
+    match subscription.receiver().try_recv().unwrap() {
+        ChangeState::Added { key, value, version, .. } => {
+            assert_eq!("my.test181.host", key);
+            assert_eq!("localhost", value);
+            assert_eq!(env_watcher.version(), version);
+        }
+        state => panic!("Unexpected state {:?}", state),
+    }
+
+    // Same value, nothing to deliver
+    assert!(env_watcher.set("my.test181.host", "localhost").unwrap().is_empty());
+
+    let changes = env_watcher
+        .apply(vec![
+            EnvUpdate::Set("my.test181.port".to_string(), "80".to_string()),
+            EnvUpdate::Set("my.test181.port".to_string(), "443".to_string()),
+            EnvUpdate::Set("my.test181.tmp".to_string(), "1".to_string()),
+            EnvUpdate::Remove("my.test181.tmp".to_string()),
+            EnvUpdate::Remove("my.test181.host".to_string()),
+        ])
+        .unwrap();
+    assert_eq!(vec!["my.test181.host", "my.test181.port"], changes.iter().map(ChangeState::key).collect::<Vec<&str>>());
+    assert_eq!(2, subscription.receiver().try_iter().filter(|state| state.version() == changes.version()).count());
+    assert_eq!(None, std::env::var("my.test181.host").ok());
+    assert_eq!(None, std::env::var("my.test181.tmp").ok());
+
+    let snapshot = env_watcher.subscribe(Subscribe::All).unwrap();
+    assert_eq!(Some("443"), snapshot.data().get("my.test181.port").map(|v| &**v));
+
+    // Nothing is written if any update is invalid
+    let invalid = env_watcher.apply(vec![
+        EnvUpdate::Set("my.test181.user".to_string(), "root".to_string()),
+        EnvUpdate::Set("my.test181=user".to_string(), "root".to_string()),
+    ]);
+    assert!(matches!(invalid, Err(Error::InvalidVariable { .. })));
+    assert_eq!(None, std::env::var("my.test181.user").ok());
+
+    // Variables outside the filter are written, but not delivered
+    assert!(env_watcher.set("my.test182.host", "localhost").unwrap().is_empty());
+    assert_eq!(Some("localhost".to_string()), std::env::var("my.test182.host").ok());
+}
+
+#[test]
+pub fn atomic_transaction() {
+    let env_watcher = EnvironmentWatcher::builder()
+        .interval(Duration::from_millis(10))
+        .filter(Subscribe::Prefix(vec!["TEST191_DB_".to_string()]))
+        .build()
+        .unwrap();
+    env_watcher.transaction().set("TEST191_DB_USER", "user0").set("TEST191_DB_PASSWORD", "password0").commit().unwrap();
+
+    let events = env_watcher.subscribe(Subscribe::All).unwrap();
+    let batch = env_watcher.subscribe_batch(Subscribe::All).unwrap();
+    let snapshot = env_watcher.subscribe_snapshot(Subscribe::All).unwrap();
+
+    let reader = {
+        let data = snapshot.ref_data();
+        std::thread::spawn(move || {
+            for _ in 0..2000 {
+                let data = data.lock().unwrap();
+                let user = data["TEST191_DB_USER"].trim_start_matches("user");
+                let password = data["TEST191_DB_PASSWORD"].trim_start_matches("password");
+                assert_eq!(user, password, "Snapshot has been observed half-updated");
+            }
+        })
+    };
+
+    for i in 1..=20 {
+        let changes = env_watcher
+            .transaction()
+            .set("TEST191_DB_USER", format!("user{}", i))
+            .set("TEST191_DB_PASSWORD", format!("password{}", i))
+            .commit()
+            .unwrap();
+        assert_eq!(2, changes.len());
+        assert!(changes.iter().all(|change| change.version() == changes.version()));
+    }
+    reader.join().unwrap();
+
+    let sets = batch.receiver().try_iter().collect::<Vec<ChangeSet>>();
+    assert_eq!(20, sets.len());
+    assert!(sets.iter().all(|set| set.len() == 2));
+    assert_eq!(40, events.receiver().try_iter().count());
+
+    // Invalid transaction writes nothing
+    let invalid = env_watcher.transaction().set("TEST191_DB_USER", "root").remove("").commit();
+    assert!(matches!(invalid, Err(Error::InvalidVariable { .. })));
+    assert_eq!(Some("user20".to_string()), std::env::var("TEST191_DB_USER").ok());
+
+    // Dropped transaction writes nothing
+    drop(env_watcher.transaction().set("TEST191_DB_USER", "root"));
+    assert_eq!(Some("user20".to_string()), std::env::var("TEST191_DB_USER").ok());
+}
+
+#[test]
+pub fn adaptive_schedule() {
+    let interval = Duration::from_millis(100);
+    let mut schedule = Schedule::new(interval, Some(Duration::from_millis(700)), 0.0);
+    let quiet = (0..5).map(|_| schedule.next(false).as_millis()).collect::<Vec<u128>>();
+    assert_eq!(vec![200, 400, 700, 700, 700], quiet);
+    assert_eq!(interval, schedule.next(true));
+    assert_eq!(Duration::from_millis(200), schedule.next(false));
+
+    // New interval, the backoff starts over
+    schedule.set_interval(Duration::from_millis(50));
+    assert_eq!(Duration::from_millis(50), schedule.delay());
+    assert_eq!(Duration::from_millis(100), schedule.next(false));
+
+    let mut fixed = Schedule::new(interval, None, 0.0);
+    assert!((0..5).all(|_| fixed.next(false) == interval));
+
+    let mut jittered = Schedule::new(interval, None, 0.2);
+    let delays = (0..100).map(|_| jittered.next(false)).collect::<Vec<Duration>>();
+    assert!(delays.iter().all(|d| *d >= Duration::from_millis(80) && *d <= Duration::from_millis(120)));
+    assert!(delays.iter().any(|d| *d != delays[0]), "Jitter has not changed the delay");
+
+    // Non-finite jitter is ignored instead of panicking in the polling thread
+    for jitter in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        let mut schedule = Schedule::new(interval, None, jitter);
+        assert!((0..5).all(|_| schedule.next(false) == interval));
+    }
+}
+
+#[test]
+pub fn set_interval_at_runtime() {
+    let env_watcher = EnvironmentWatcher::builder()
+        .interval(Duration::from_secs(30))
+        .filter(Subscribe::Prefix(vec!["my.test201.".to_string()]))
+        .build()
+        .unwrap();
+    let sub = env_watcher.subscribe(Subscribe::All).unwrap();
+    sleep(Duration::from_millis(50));
+
+    set_var("my.test201.host", TEST_VALUE);
+    assert!(sub.receiver().recv_timeout(Duration::from_millis(300)).is_err());
+
+    env_watcher.set_interval(Duration::from_millis(10));
+    assert_eq!(Duration::from_millis(10), env_watcher.interval());
+    let change = sub.receiver().recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(added("my.test201.host", TEST_VALUE).key(), change.key());
+}
+
+/// Source controlled by the test
+#[derive(Clone, Default)]
+struct TestSource {
+    vars: Arc<Mutex<HashMap<String, String>>>,
+    fail: Arc<AtomicBool>,
+    changed: Arc<AtomicBool>,
+}
+
+impl TestSource {
+    fn set(&self, key: &str, value: &str) {
+        self.vars.lock().unwrap().insert(key.to_string(), value.to_string());
+        self.changed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl EnvSource for TestSource {
+    fn name(&self) -> &str {
+        "test"
+    }
+
+    fn snapshot(&mut self) -> Result<HashMap<String, String>> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(Error::Source { name: "test".to_string(), error: "unavailable".to_string() });
+        }
+        Ok(self.vars.lock().unwrap().clone())
+    }
+
+    fn changed(&mut self) -> bool {
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[test]
+pub fn custom_sources() {
+    set_var("my.test211.host", "process");
+    set_var("my.test211.port", "80");
+    let source = TestSource::default();
+    source.set("my.test211.host", "source");
+
+    let env_watcher = EnvironmentWatcher::builder()
+        .manual(true)
+        .filter(Subscribe::Prefix(vec!["my.test211.".to_string()]))
+        .source(ProcessEnv)
+        .source(source.clone())
+        .build()
+        .unwrap();
+
+    // Later sources override earlier ones
+    let sub = env_watcher.subscribe(Subscribe::All).unwrap();
+    assert_eq!(Some(&"source".to_string()), sub.data().get("my.test211.host"));
+    assert_eq!(Some(&"80".to_string()), sub.data().get("my.test211.port"));
+
+    // Without the change hint the cached snapshot is used
+    source.changed.store(false, Ordering::SeqCst);
+    source.vars.lock().unwrap().insert("my.test211.host".to_string(), "hidden".to_string());
+    assert!(env_watcher.poll_now().is_empty());
+    source.set("my.test211.host", "updated");
+    let changes = env_watcher.poll_now();
+    let change = changes.iter().next().unwrap();
+    assert_eq!(1, changes.len());
+    assert_eq!((Some("source"), Some("updated")), (change.old(), change.value()));
+
+    // A failing source keeps its last snapshot
+    source.fail.store(true, Ordering::SeqCst);
+    source.set("my.test211.host", "lost");
+    assert!(env_watcher.poll_now().is_empty());
+    source.fail.store(false, Ordering::SeqCst);
+    source.changed.store(true, Ordering::SeqCst);
+    assert_eq!(1, env_watcher.poll_now().len());
+    assert_eq!(Some("lost"), sub.receiver().try_iter().last().as_ref().and_then(|c| c.value()));
+}
+
+#[test]
+pub fn parse_dotenv_syntax() {
+    let content = "# comment\n\
+        \n\
+        HOST=localhost\n\
+        export PORT = 8080   # inline comment\n\
+        URL=http://host/#anchor\n\
+        LITERAL='$HOME \\n # not a comment'\n\
+        ESCAPED=\"tab\\tquote\\\" slash\\\\\" # comment\n\
+        MULTI=\"first\n\
+        second\"\n\
+        SINGLE_MULTI='a\n\
+        b'\n\
+        EMPTY=\n\
+        my.test221.key=dotted\n\
+        HOST=override\n";
+    let vars = parse_dotenv(content).unwrap();
+    let expected = [
+        ("HOST", "override"),
+        ("PORT", "8080"),
+        ("URL", "http://host/#anchor"),
+        ("LITERAL", "$HOME \\n # not a comment"),
+        ("ESCAPED", "tab\tquote\" slash\\"),
+        ("MULTI", "first\nsecond"),
+        ("SINGLE_MULTI", "a\nb"),
+        ("EMPTY", ""),
+        ("my.test221.key", "dotted"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect::<HashMap<String, String>>();
+    assert_eq!(expected, vars);
+
+    let line = |content: &str| match parse_dotenv(content) {
+        Err(Error::Parse { line, .. }) => line,
+        other => panic!("Expected a parse error, found {:?}", other),
+    };
+    assert_eq!(2, line("A=1\nB\nC=3"));
+    assert_eq!(3, line("A=1\n\nB=\"open\nstill open"));
+    assert_eq!(1, line("A='value' trailing"));
+    assert_eq!(2, line("A=1\n1A=2"));
+    assert_eq!(1, line("=value"));
+}
+
+#[test]
+pub fn watch_dotenv_file() {
+    let path = std::env::temp_dir().join(format!("env-watcher-test222-{}.env", std::process::id()));
+    std::fs::write(&path, "HOST=localhost\nPORT=8080\n").unwrap();
+
+    let env_watcher = EnvironmentWatcher::builder().manual(true).source(DotEnvFile::new(&path)).build().unwrap();
+    let sub = env_watcher.subscribe(Subscribe::All).unwrap();
+    assert_eq!(2, sub.data().len());
+    assert_eq!(Some(&"8080".to_string()), sub.data().get("PORT"));
+    assert!(env_watcher.poll_now().is_empty());
+
+    // Same size, detected within the racy window by the content hash
+    std::fs::write(&path, "HOST=localhost\nPORT=8443\n").unwrap();
+    let changes = env_watcher.poll_now();
+    assert_eq!(1, changes.len());
+    assert_eq!(Some("8443"), changes.iter().next().unwrap().value());
+
+    // An invalid file keeps the last variables
+    std::fs::write(&path, "HOST=localhost\nPORT='8443\n").unwrap();
+    assert!(env_watcher.poll_now().is_empty());
+
+    std::fs::write(&path, "export HOST=example.com\n").unwrap();
+    let changes = env_watcher.poll_now();
+    assert_eq!(2, changes.len());
+    assert_eq!(Some("example.com"), changes.iter().find(|c| c.key() == "HOST").unwrap().value());
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(env_watcher.poll_now().is_empty());
+    assert_eq!(1, env_watcher.subscribe(Subscribe::All).unwrap().data().len());
+}
+
+#[test]
+pub fn dotenv_racy_window_expires() {
+    let path = std::env::temp_dir().join(format!("env-watcher-test223-{}.env", std::process::id()));
+    let write = |content: &str, modified: SystemTime| {
+        std::fs::write(&path, content).unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+    };
+    // Modified shortly before the first read, compared by hash for a while
+    let modified = SystemTime::now() - Duration::from_millis(1500);
+    write("PORT=8080\n", modified);
+    let mut source = DotEnvFile::new(&path);
+    source.snapshot().unwrap();
+    assert!(!source.changed());
+
+    // Out of the window the hash is no longer compared, a new modification time is required
+    sleep(Duration::from_millis(700));
+    assert!(!source.changed());
+    write("PORT=8443\n", modified);
+    assert!(!source.changed());
+    write("PORT=8443\n", SystemTime::now());
+    assert!(source.changed());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+pub fn watch_kubernetes_directory() {
+    use std::os::unix::fs::symlink;
+
+    let dir = std::env::temp_dir().join(format!("env-watcher-test231-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let write_data = |version: &str, vars: &[(&str, &str)]| {
+        let data = dir.join(version);
+        std::fs::create_dir_all(&data).unwrap();
+        for (key, value) in vars {
+            std::fs::write(data.join(key), value).unwrap();
+        }
+        // Atomic swap of the link, like the kubelet does
+        let tmp = dir.join("..data_tmp");
+        symlink(version, &tmp).unwrap();
+        std::fs::rename(&tmp, dir.join("..data")).unwrap();
+    };
+    write_data("..v1", &[("DB_USER", "user1\n"), ("DB_PASSWORD", "password1\n")]);
+    for key in ["DB_USER", "DB_PASSWORD"] {
+        symlink(format!("..data/{}", key), dir.join(key)).unwrap();
+    }
+
+    let env_watcher = EnvironmentWatcher::builder()
+        .manual(true)
+        .source(DirectorySource::new(&dir).trim_newlines(true))
+        .build()
+        .unwrap();
+    let batch = env_watcher.subscribe_batch(Subscribe::All).unwrap();
+    assert_eq!(Some(&"password1".to_string()), batch.data().get("DB_PASSWORD"));
+    assert_eq!(2, batch.data().len());
+    assert!(env_watcher.poll_now().is_empty());
+
+    write_data("..v2", &[("DB_USER", "user2\n"), ("DB_PASSWORD", "password2\n")]);
+    std::fs::remove_dir_all(dir.join("..v1")).unwrap();
+    env_watcher.poll_now();
+    let changes = batch.receiver().try_recv().unwrap();
+    assert_eq!(2, changes.len());
+    assert_eq!(vec![Some("password2"), Some("user2")], changes.iter().map(|c| c.value()).collect::<Vec<_>>());
+
+    // Without trimming the content is kept as is, a plain directory is read too
+    let plain = DirectorySource::new(dir.join("..v2")).snapshot().unwrap();
+    assert_eq!(Some(&"user2\n".to_string()), plain.get("DB_USER"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+pub fn resolve_file_pointers() {
+    let dir = std::env::temp_dir();
+    let first = dir.join(format!("env-watcher-test241-first-{}", std::process::id()));
+    let second = dir.join(format!("env-watcher-test241-second-{}", std::process::id()));
+    std::fs::write(&first, "secret1\n").unwrap();
+    std::fs::write(&second, "secret2").unwrap();
+
+    let source = TestSource::default();
+    source.set("DB_PASSWORD_FILE", first.to_str().unwrap());
+    let env_watcher = EnvironmentWatcher::builder().manual(true).source(source.clone()).resolve_files(true).build().unwrap();
+    let sub = env_watcher.subscribe(Subscribe::Envs(vec!["DB_PASSWORD".to_string()])).unwrap();
+    assert_eq!(Some(&"secret1".to_string()), sub.data().get("DB_PASSWORD"));
+
+    // The file content changes
+    std::fs::write(&first, "rotated1\n").unwrap();
+    env_watcher.poll_now();
+    assert_eq!(Some("rotated1"), sub.receiver().try_recv().unwrap().value());
+
+    // The pointer changes
+    source.set("DB_PASSWORD_FILE", second.to_str().unwrap());
+    env_watcher.poll_now();
+    assert_eq!(Some("secret2"), sub.receiver().try_recv().unwrap().value());
+
+    // An unreadable file keeps the last content of the same path
+    std::fs::remove_file(&second).unwrap();
+    source.changed.store(true, Ordering::SeqCst);
+    assert!(env_watcher.poll_now().is_empty());
+
+    // The explicit variable wins
+    source.set("DB_PASSWORD", "explicit");
+    env_watcher.poll_now();
+    assert_eq!(Some("explicit"), sub.receiver().try_recv().unwrap().value());
+
+    // Opt-in only
+    source.vars.lock().unwrap().remove("DB_PASSWORD");
+    source.set("DB_PASSWORD_FILE", first.to_str().unwrap());
+    let plain = EnvironmentWatcher::builder().manual(true).source(source.clone()).build().unwrap();
+    let data = plain.subscribe(Subscribe::All).unwrap();
+    assert_eq!(None, data.data().get("DB_PASSWORD"));
+    assert!(data.data().contains_key("DB_PASSWORD_FILE"));
+
+    std::fs::remove_file(&first).unwrap();
+}
+
+#[test]
+pub fn layered_sources_origin() {
+    let defaults = TestSource::default();
+    defaults.set("HOST", "localhost");
+    defaults.set("PORT", "8080");
+    defaults.set("LOG", "info");
+    let overrides = TestSource::default();
+    overrides.set("PORT", "9090");
+    let secrets = TestSource::default();
+    secrets.set("LOG", "info");
+
+    let env_watcher = EnvironmentWatcher::builder()
+        .manual(true)
+        .layer("defaults", defaults.clone())
+        .layer("overrides", overrides.clone())
+        .layer("secrets", secrets.clone())
+        .build()
+        .unwrap();
+    let sub = env_watcher.subscribe(Subscribe::All).unwrap();
+    assert_eq!(Some(&"9090".to_string()), sub.data().get("PORT"));
+    assert_eq!(Some("defaults".to_string()), env_watcher.origin("HOST"));
+    assert_eq!(Some("overrides".to_string()), env_watcher.origin("PORT"));
+    assert_eq!(Some("secrets".to_string()), env_watcher.origin("LOG"));
+    assert_eq!(None, env_watcher.origin("MISSING"));
+
+    // A change hidden by a later layer is not an effective change
+    defaults.set("PORT", "8081");
+    assert!(env_watcher.poll_now().is_empty());
+
+    // Removing the override falls back to the earlier layer
+    overrides.vars.lock().unwrap().remove("PORT");
+    overrides.changed.store(true, Ordering::SeqCst);
+    let changes = env_watcher.poll_now();
+    assert_eq!(1, changes.len());
+    assert_eq!((Some("9090"), Some("8081")), (changes.iter().next().unwrap().old(), changes.iter().next().unwrap().value()));
+    assert_eq!(Some("defaults".to_string()), env_watcher.origin("PORT"));
+
+    // Same effective value from another layer: no event, the origin follows
+    secrets.vars.lock().unwrap().remove("LOG");
+    secrets.changed.store(true, Ordering::SeqCst);
+    assert!(env_watcher.poll_now().is_empty());
+    assert_eq!(Some("defaults".to_string()), env_watcher.origin("LOG"));
+
+    // Sources are named by EnvSource::name, write-through updates come from the process layer
+    let process = EnvironmentWatcher::builder()
+        .manual(true)
+        .filter(Subscribe::Prefix(vec!["my.test251.".to_string()]))
+        .source(ProcessEnv)
+        .source(secrets.clone())
+        .build()
+        .unwrap();
+    process.set("my.test251.host", "example.com").unwrap();
+    assert_eq!(Some("process".to_string()), process.origin("my.test251.host"));
+    process.remove("my.test251.host").unwrap();
+    assert_eq!(None, process.origin("my.test251.host"));
+    secrets.set("my.test251.port", "443");
+    process.poll_now();
+    assert_eq!(Some("test".to_string()), process.origin("my.test251.port"));
+}
+
+#[test]
+pub fn debounced_write_through_wakes_thread() {
+    let env_watcher = EnvironmentWatcher::builder()
+        .interval(Duration::from_secs(5))
+        .filter(Subscribe::Prefix(vec!["my.test181.debounce.".to_string()]))
+        .build()
+        .unwrap();
+    let debounced = env_watcher.subscribe_debounced(Subscribe::All, Duration::from_millis(100)).unwrap();
+    sleep(Duration::from_millis(50));
+
+    // Delivered after the quiet window, long before the next poll
+    env_watcher.set("my.test181.debounce.host", "localhost").unwrap();
+    let set = debounced.receiver().recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(1, set.len());
+}
+
+#[test]
+pub fn write_through_before_first_poll() {
+    set_var("my.test183.port", "80");
+    let env_watcher = EnvironmentWatcher::builder()
+        .manual(true)
+        .preload(false)
+        .filter(Subscribe::Prefix(vec!["my.test183.".to_string()]))
+        .build()
+        .unwrap();
+    let subscription = env_watcher.subscribe(Subscribe::Envs(vec!["my.test183.host".to_string()])).unwrap();
+
+    // The environment has not been read yet, the write is still delivered at once
+    let changes = env_watcher.set("my.test183.host", "localhost").unwrap();
+    assert_eq!(2, changes.len());
+    assert_eq!(Some("localhost"), subscription.receiver().try_recv().unwrap().value());
+    assert!(env_watcher.poll_now().is_empty());
+    assert!(subscription.receiver().try_recv().is_err());
+}
+
+#[test]
+pub fn snapshot_channel_is_unbounded() {
+    let env_watcher = EnvironmentWatcher::builder()
+        .manual(true)
+        .channel(ChannelPolicy::Bounded(1))
+        .filter(Subscribe::Prefix(vec!["my.test151.".to_string()]))
+        .build()
+        .unwrap();
+    let batch = env_watcher.subscribe_batch(Subscribe::All).unwrap();
+    let snapshot = env_watcher.subscribe_snapshot(Subscribe::All).unwrap();
+
+    let capacity = |id: u64| match &env_watcher.shared.senders.lock().unwrap().subscribers[&id].tx {
+        Delivery::Batch(tx) => tx.capacity(),
+        _ => panic!("Expected a batch subscriber"),
+    };
+    assert_eq!(Some(1), capacity(batch.id));
+    assert_eq!(None, capacity(snapshot.subscription.id));
+
+    for i in 0..10 {
+        env_watcher.set("my.test151.port", i.to_string()).unwrap();
+    }
+    let mut x = 0;
+    while snapshot.data().get("my.test151.port").map(String::as_str) != Some("9") {
+        assert!(x < 50, "Snapshot has missed a change");
+        x += 1;
+        sleep(Duration::from_millis(10));
+    }
+    assert_eq!(1, batch.receiver().try_iter().count());
+}
+
+#[test]
+pub fn write_through_without_process_source() {
+    let env_watcher = EnvironmentWatcher::builder().manual(true).source(TestSource::default()).build().unwrap();
+    let sub = env_watcher.subscribe(Subscribe::All).unwrap();
+
+    // Written to the process environment, but no source reads it
+    assert!(env_watcher.set("my.test212.host", "localhost").unwrap().is_empty());
+    assert_eq!(Some("localhost".to_string()), std::env::var("my.test212.host").ok());
+    assert!(env_watcher.poll_now().is_empty());
+    assert!(sub.receiver().try_recv().is_err());
+    assert_eq!(0, env_watcher.version());
+    assert_eq!(None, env_watcher.origin("my.test212.host"));
+}
+
+#[test]
+pub fn write_through_respects_layers() {
+    let defaults = TestSource::default();
+    defaults.set("my.test253.port", "8080");
+    let secrets = TestSource::default();
+    secrets.set("my.test253.password", "fromsecret");
+
+    let env_watcher = EnvironmentWatcher::builder()
+        .manual(true)
+        .filter(Subscribe::Prefix(vec!["my.test253.".to_string()]))
+        .layer("defaults", defaults)
+        .layer("env", ProcessEnv)
+        .layer("secrets", secrets)
+        .build()
+        .unwrap();
+    let sub = env_watcher.subscribe(Subscribe::All).unwrap();
+
+    // A later layer keeps the effective value
+    assert!(env_watcher.set("my.test253.password", "fromenv").unwrap().is_empty());
+    assert_eq!(Some("secrets".to_string()), env_watcher.origin("my.test253.password"));
+    assert!(env_watcher.poll_now().is_empty());
+    assert!(sub.receiver().try_recv().is_err());
+
+    // An earlier layer is overridden, removing the variable falls back to it
+    let changes = env_watcher.set("my.test253.port", "9090").unwrap();
+    assert_eq!(Some("9090"), changes.iter().next().unwrap().value());
+    assert_eq!(Some("env".to_string()), env_watcher.origin("my.test253.port"));
+    let changes = env_watcher.remove("my.test253.port").unwrap();
+    assert_eq!((Some("9090"), Some("8080")), (changes.iter().next().unwrap().old(), changes.iter().next().unwrap().value()));
+    assert_eq!(Some("defaults".to_string()), env_watcher.origin("my.test253.port"));
+    assert!(env_watcher.poll_now().is_empty());
+    assert_eq!(2, sub.receiver().try_iter().count());
+}
+
+#[test]
+pub fn file_pointer_layer_precedence() {
+    let path = std::env::temp_dir().join(format!("env-watcher-test254-{}", std::process::id()));
+    std::fs::write(&path, "fromfile\n").unwrap();
+
+    let defaults = TestSource::default();
+    defaults.set("DB_PASSWORD", "changeme");
+    defaults.set("DB_USER_FILE", path.to_str().unwrap());
+    let env = TestSource::default();
+    env.set("DB_PASSWORD_FILE", path.to_str().unwrap());
+    env.set("DB_USER", "explicit");
+
+    let env_watcher = EnvironmentWatcher::builder()
+        .manual(true)
+        .layer("defaults", defaults)
+        .layer("env", env)
+        .resolve_files(true)
+        .build()
+        .unwrap();
+    let data = env_watcher.subscribe(Subscribe::Envs(vec!["DB_PASSWORD".to_string(), "DB_USER".to_string()])).unwrap();
+
+    // The later layer wins, whether it defines the pointer or the value
+    assert_eq!(Some(&"fromfile".to_string()), data.data().get("DB_PASSWORD"));
+    assert_eq!(Some("env".to_string()), env_watcher.origin("DB_PASSWORD"));
+    assert_eq!(Some(&"explicit".to_string()), data.data().get("DB_USER"));
+    assert_eq!(Some("env".to_string()), env_watcher.origin("DB_USER"));
+
+    std::fs::remove_file(&path).unwrap();
+}